@@ -0,0 +1,515 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::dns::{
+    answer::DnsAnswer,
+    authority::DnsAuthority,
+    common::{DnsClass, DnsName, DnsType},
+    header::{DnsHeaderRcode, DnsHeaderTC},
+    message::DnsMessage,
+    question::DnsQuestion,
+    rdata::RData,
+};
+
+/// Longest we keep anything, whatever TTL it came with (RFC 8767 section 4).
+pub const MAX_CACHE_TTL: u32 = 604800;
+
+/// CNAMEs followed when answering from the cache before giving up and asking upstream.
+const MAX_CNAME_CHAIN: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: DnsName,
+    // unset for facts about the whole name, i.e. NXDOMAIN
+    pub qtype: Option<DnsType>,
+    pub qclass: DnsClass,
+}
+
+impl CacheKey {
+    pub fn new(name: DnsName, qtype: DnsType, qclass: DnsClass) -> Self {
+        Self {
+            name,
+            qtype: Some(qtype),
+            qclass,
+        }
+    }
+
+    pub fn for_name(name: DnsName, qclass: DnsClass) -> Self {
+        Self {
+            name,
+            qtype: None,
+            qclass,
+        }
+    }
+}
+
+/// What the cache knows about a key.
+#[derive(Debug, Clone)]
+pub enum CacheData {
+    Records(Vec<DnsAnswer>),
+    // NXDOMAIN or NODATA (RFC 2308), with the SOA to return in the authority section; the SOA
+    // TTL is the negative TTL
+    Negative {
+        rcode: DnsHeaderRcode,
+        soa: DnsAuthority,
+    },
+}
+
+impl CacheData {
+    fn records(&self) -> Vec<&DnsAnswer> {
+        match self {
+            CacheData::Records(records) => records.iter().collect(),
+            CacheData::Negative { soa, .. } => vec![soa],
+        }
+    }
+
+    fn map_records(&self, f: impl Fn(&DnsAnswer) -> DnsAnswer) -> Self {
+        match self {
+            CacheData::Records(records) => CacheData::Records(records.iter().map(f).collect()),
+            CacheData::Negative { rcode, soa } => CacheData::Negative {
+                rcode: *rcode,
+                soa: f(soa),
+            },
+        }
+    }
+}
+
+/// A response assembled from the cache.
+#[derive(Debug, Clone)]
+pub struct CachedAnswer {
+    pub rcode: DnsHeaderRcode,
+    pub answers: Vec<DnsAnswer>,
+    pub authorities: Vec<DnsAuthority>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    // records as received, TTLs are adjusted when served
+    data: CacheData,
+    inserted: Instant,
+    expires: Instant,
+    // position in the LRU order
+    last_used: u64,
+    // approximate memory held, counted as wire format size
+    size: usize,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // least recently used first
+    lru: BTreeMap<u64, CacheKey>,
+    // soonest to expire first
+    expiry: BTreeMap<(Instant, u64), CacheKey>,
+    tick: u64,
+    size: usize,
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.expiry.remove(&(entry.expires, entry.last_used));
+            self.size -= entry.size;
+        }
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_used);
+            self.expiry.remove(&(entry.expires, entry.last_used));
+            entry.last_used = tick;
+            self.lru.insert(tick, key.clone());
+            self.expiry.insert((entry.expires, tick), key.clone());
+        }
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        while let Some(((expires, _), key)) = self.expiry.first_key_value() {
+            if *expires > now {
+                break;
+            }
+            let key = key.clone();
+            self.remove(&key);
+        }
+    }
+}
+
+/// In-memory cache of RRsets and negative answers keyed by owner name, type and class. Entries
+/// leave when their TTL runs out, and the least recently used go first once either bound is
+/// exceeded.
+#[derive(Debug)]
+pub struct Cache {
+    pub max_entries: usize,
+    pub max_size: usize,
+    state: Mutex<CacheState>,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize, max_size: usize) -> Self {
+        Self {
+            max_entries,
+            max_size,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The cached data for `key`, with TTLs reduced by the time it has spent in the cache.
+    pub fn get(&self, key: &CacheKey) -> Option<CacheData> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &CacheKey, now: Instant) -> Option<CacheData> {
+        let mut state = self.state.lock().unwrap();
+        state.remove_expired(now);
+        state.touch(key);
+
+        let entry = state.entries.get(key)?;
+        let elapsed = now.duration_since(entry.inserted).as_secs() as u32;
+        Some(entry.data.map_records(|record| DnsAnswer {
+            ttl: record.ttl.min(MAX_CACHE_TTL).saturating_sub(elapsed),
+            ..record.clone()
+        }))
+    }
+
+    /// Stores one RRset, replacing whatever was cached under the same key. The set lives as long
+    /// as its lowest TTL; sets with a TTL of zero are not stored.
+    pub fn insert(&self, records: Vec<DnsAnswer>) {
+        self.insert_at(records, Instant::now());
+    }
+
+    fn insert_at(&self, records: Vec<DnsAnswer>, now: Instant) {
+        let Some(first) = records.first() else {
+            return;
+        };
+        let key = CacheKey::new(first.name.clone(), first.qtype, first.qclass);
+        self.store(key, CacheData::Records(records), now);
+    }
+
+    fn store(&self, key: CacheKey, data: CacheData, now: Instant) {
+        let records = data.records();
+        let ttl = records.iter().map(|record| record.ttl).min().unwrap_or(0);
+        let size: usize = records.iter().map(|record| record.as_buf().len()).sum();
+        if ttl == 0 || self.max_entries == 0 || size > self.max_size {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.remove(&key);
+        state.remove_expired(now);
+        while state.entries.len() >= self.max_entries || state.size + size > self.max_size {
+            let Some((_, oldest)) = state.lru.first_key_value() else {
+                break;
+            };
+            let oldest = oldest.clone();
+            state.remove(&oldest);
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        let expires = now + Duration::from_secs(ttl.min(MAX_CACHE_TTL) as u64);
+        state.lru.insert(tick, key.clone());
+        state.expiry.insert((expires, tick), key.clone());
+        state.size += size;
+        state.entries.insert(
+            key,
+            CacheEntry {
+                data,
+                inserted: now,
+                expires,
+                last_used: tick,
+                size,
+            },
+        );
+    }
+
+    /// Answers `question` from the cache, following cached CNAMEs. Returns `None` unless the
+    /// whole chain down to the requested type, or a negative answer for its end, is present.
+    pub fn lookup(&self, question: &DnsQuestion) -> Option<CachedAnswer> {
+        let mut answers = vec![];
+        let mut name = question.name.clone();
+
+        for _ in 0..=MAX_CNAME_CHAIN {
+            let key = CacheKey::new(name.clone(), question.qtype, question.qclass);
+            let nxdomain_key = CacheKey::for_name(name.clone(), question.qclass);
+            let (rcode, authorities) = match self.get(&key).or_else(|| self.get(&nxdomain_key)) {
+                Some(CacheData::Records(records)) => {
+                    answers.extend(records);
+                    (DnsHeaderRcode::NoError, vec![])
+                }
+                Some(CacheData::Negative { rcode, soa }) => (rcode, vec![soa]),
+                None if question.qtype == DnsType::CNAME => return None,
+                None => {
+                    let key = CacheKey::new(name, DnsType::CNAME, question.qclass);
+                    let Some(CacheData::Records(records)) = self.get(&key) else {
+                        return None;
+                    };
+                    let RData::CNAME(target) = &records.first()?.data else {
+                        return None;
+                    };
+                    name = target.clone();
+                    answers.extend(records);
+                    continue;
+                }
+            };
+
+            return Some(CachedAnswer {
+                rcode,
+                answers,
+                authorities,
+            });
+        }
+
+        None
+    }
+
+    /// Caches what a complete upstream reply to `question` says. Only records for the question
+    /// name and the CNAME chain leading from it are trusted. NXDOMAIN and NODATA replies are
+    /// remembered for the name the chain ends at, for as long as their SOA allows (RFC 2308
+    /// section 5); without an SOA they are not cached at all.
+    pub fn insert_reply(&self, question: &DnsQuestion, reply: &DnsMessage) {
+        self.insert_reply_at(question, reply, Instant::now());
+    }
+
+    fn insert_reply_at(&self, question: &DnsQuestion, reply: &DnsMessage, now: Instant) {
+        if !matches!(
+            reply.header.rcode,
+            DnsHeaderRcode::NoError | DnsHeaderRcode::NameError
+        ) || reply.header.truncation == DnsHeaderTC::Truncated
+        {
+            return;
+        }
+
+        let chain = cname_chain(question, &reply.answers);
+        let mut rrsets: HashMap<CacheKey, Vec<DnsAnswer>> = HashMap::new();
+        reply
+            .answers
+            .iter()
+            .filter(|answer| answer.qclass == question.qclass && chain.contains(&answer.name))
+            .for_each(|answer| {
+                let key = CacheKey::new(answer.name.clone(), answer.qtype, answer.qclass);
+                rrsets.entry(key).or_default().push(answer.clone());
+            });
+
+        let last = chain.last().expect("the chain starts at the question name");
+        let answered = question.qtype == DnsType::CNAME && chain.len() > 1
+            || rrsets.contains_key(&CacheKey::new(
+                last.clone(),
+                question.qtype,
+                question.qclass,
+            ));
+        let negative_key = match reply.header.rcode {
+            DnsHeaderRcode::NameError => Some(CacheKey::for_name(last.clone(), question.qclass)),
+            _ if !answered => Some(CacheKey::new(last.clone(), question.qtype, question.qclass)),
+            _ => None,
+        };
+
+        rrsets
+            .into_values()
+            .for_each(|records| self.insert_at(records, now));
+
+        let soa = reply
+            .authorities
+            .iter()
+            .find_map(|record| match &record.data {
+                RData::SOA(soa) if record.qclass == question.qclass => Some((record, soa)),
+                _ => None,
+            });
+        if let (Some(key), Some((record, soa))) = (negative_key, soa) {
+            let soa = DnsAuthority {
+                ttl: record.ttl.min(soa.minimum),
+                ..record.clone()
+            };
+            let rcode = reply.header.rcode;
+            self.store(key, CacheData::Negative { rcode, soa }, now);
+        }
+    }
+}
+
+/// Names from the question name down the CNAME chain found in `answers`, in order.
+pub fn cname_chain(question: &DnsQuestion, answers: &[DnsAnswer]) -> Vec<DnsName> {
+    let mut chain = vec![question.name.clone()];
+    while chain.len() <= MAX_CNAME_CHAIN {
+        let last = chain.last().expect("the chain is never empty");
+        let target = answers.iter().find_map(|answer| match &answer.data {
+            RData::CNAME(target) if answer.qclass == question.qclass && answer.name == *last => {
+                Some(target.clone())
+            }
+            _ => None,
+        });
+        match target {
+            Some(target) if !chain.contains(&target) => chain.push(target),
+            _ => break,
+        }
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant},
+    };
+
+    use super::{Cache, CacheData, CacheKey};
+    use crate::dns::{
+        answer::DnsAnswer,
+        authority::DnsAuthority,
+        common::{DnsClass, DnsName, DnsType},
+        header::{DnsHeader, DnsHeaderRcode},
+        message::DnsMessage,
+        question::DnsQuestion,
+        rdata::{DnsSoa, RData},
+    };
+
+    fn a_record(name: &str, ttl: u32, last_octet: u8) -> DnsAnswer {
+        let rdata = RData::A(Ipv4Addr::new(192, 0, 2, last_octet));
+        DnsAnswer::new(name, DnsType::A, DnsClass::IN, ttl, rdata)
+    }
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey::new(DnsName::new(name.into()), DnsType::A, DnsClass::IN)
+    }
+
+    #[test]
+    fn test_cache_counts_down_ttls_and_expires() {
+        let cache = Cache::new(16, 4096);
+        let now = Instant::now();
+        cache.insert_at(vec![a_record("example.com", 60, 1)], now);
+
+        let Some(CacheData::Records(records)) =
+            cache.get_at(&key("EXAMPLE.com."), now + Duration::from_secs(15))
+        else {
+            panic!("expected cached records");
+        };
+        assert_eq!(records[0].ttl, 45);
+
+        assert!(cache
+            .get_at(&key("example.com"), now + Duration::from_secs(60))
+            .is_none());
+        assert!(cache.is_empty());
+
+        cache.insert_at(vec![a_record("zero.example.com", 0, 1)], now);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let cache = Cache::new(2, 4096);
+        cache.insert(vec![a_record("one.example.com", 60, 1)]);
+        cache.insert(vec![a_record("two.example.com", 60, 2)]);
+        // reading one makes two the oldest
+        assert!(cache.get(&key("one.example.com")).is_some());
+        cache.insert(vec![a_record("three.example.com", 60, 3)]);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&key("two.example.com")).is_none());
+        assert!(cache.get(&key("one.example.com")).is_some());
+
+        // one record is 31 bytes on the wire, so only one fits
+        let cache = Cache::new(16, 40);
+        cache.insert(vec![a_record("one.example.com", 60, 1)]);
+        cache.insert(vec![a_record("two.example.com", 60, 2)]);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&key("two.example.com")).is_some());
+    }
+
+    #[test]
+    fn test_cache_answers_through_cnames() {
+        let cache = Cache::new(16, 4096);
+        let question = DnsQuestion::new("www.example.com", DnsType::A, DnsClass::IN);
+        let header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        let cname = RData::CNAME(DnsName::new("web.example.com".into()));
+        let answers = vec![
+            DnsAnswer::new("www.example.com", DnsType::CNAME, DnsClass::IN, 300, cname),
+            a_record("web.example.com", 60, 1),
+            a_record("web.example.com", 60, 2),
+            // not on the chain, so not trusted
+            a_record("elsewhere.example.net", 60, 3),
+        ];
+        let reply = DnsMessage::new(header, vec![question.clone()], answers, vec![], vec![]);
+        cache.insert_reply(&question, &reply);
+
+        assert_eq!(cache.len(), 2);
+        let answers = cache.lookup(&question).unwrap().answers;
+        assert_eq!(answers.len(), 3);
+        assert_eq!(answers[0].qtype, DnsType::CNAME);
+        assert_eq!(answers[2].data, RData::A(Ipv4Addr::new(192, 0, 2, 2)));
+    }
+
+    #[test]
+    fn test_cache_remembers_nxdomain_and_nodata() {
+        let cache = Cache::new(16, 4096);
+        let now = Instant::now();
+        let soa = DnsSoa {
+            mname: DnsName::new("ns.example.com".into()),
+            rname: DnsName::new("hostmaster.example.com".into()),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 30,
+        };
+        let authority = DnsAuthority::new(
+            "example.com",
+            DnsType::SOA,
+            DnsClass::IN,
+            300,
+            RData::SOA(soa),
+        );
+
+        // NXDOMAIN covers every type at the name, for the SOA minimum rather than its TTL
+        let question = DnsQuestion::new("typo.example.com", DnsType::A, DnsClass::IN);
+        let mut header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        header.rcode = DnsHeaderRcode::NameError;
+        let reply = DnsMessage::new(
+            header,
+            vec![question.clone()],
+            vec![],
+            vec![authority.clone()],
+            vec![],
+        );
+        cache.insert_reply_at(&question, &reply, now);
+
+        let question = DnsQuestion::new("typo.example.com", DnsType::MX, DnsClass::IN);
+        let cached = cache.lookup(&question).unwrap();
+        assert_eq!(cached.rcode, DnsHeaderRcode::NameError);
+        assert!(cached.answers.is_empty());
+        assert_eq!(cached.authorities[0].qtype, DnsType::SOA);
+        assert!(cached.authorities[0].ttl <= 30);
+        let key = CacheKey::for_name(DnsName::new("typo.example.com".into()), DnsClass::IN);
+        assert!(cache.get_at(&key, now + Duration::from_secs(30)).is_none());
+
+        // NODATA only covers the type asked for
+        let question = DnsQuestion::new("www.example.com", DnsType::AAAA, DnsClass::IN);
+        let header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        let reply = DnsMessage::new(
+            header,
+            vec![question.clone()],
+            vec![],
+            vec![authority],
+            vec![],
+        );
+        cache.insert_reply(&question, &reply);
+
+        let cached = cache.lookup(&question).unwrap();
+        assert_eq!(cached.rcode, DnsHeaderRcode::NoError);
+        assert_eq!(cached.authorities.len(), 1);
+        let question = DnsQuestion::new("www.example.com", DnsType::A, DnsClass::IN);
+        assert!(cache.lookup(&question).is_none());
+    }
+}
@@ -6,3 +6,6 @@ pub mod answer;
 pub mod authority;
 pub mod additional;
 pub mod common;
+pub mod edns;
+pub mod rdata;
+pub mod encoder;
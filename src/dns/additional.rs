@@ -1,9 +1,4 @@
+use super::answer::DnsAnswer;
 
-#[derive(Debug)]
-pub struct DnsAdditional {
-    name: String,
-    qtype: u16,
-    qclass: u16,
-    ttl: u32,
-    data: Vec<u8>,
-}
+/// Additional section records (glue, EDNS) share the answer format.
+pub type DnsAdditional = DnsAnswer;
@@ -1,72 +1,99 @@
-use std::str;
+use std::fmt;
 
 use bytes::{BufMut, BytesMut};
 
-use super::common::{DnsClass, DnsName, DnsType};
+use crate::error::DnsError;
 
-#[derive(Debug)]
+use super::{
+    common::{read_u16, read_u32, DnsClass, DnsName, DnsType},
+    encoder::DnsEncoder,
+    rdata::RData,
+};
+
+#[derive(Debug, Clone)]
 pub struct DnsAnswer {
-    name: DnsName,
-    qtype: DnsType,
-    qclass: DnsClass,
-    ttl: u32,
-    length: usize,
-    data: Vec<u8>,
+    pub name: DnsName,
+    pub qtype: DnsType,
+    pub qclass: DnsClass,
+    pub ttl: u32,
+    pub data: RData,
 }
 
 impl DnsAnswer {
-    pub fn new(name: &str, qtype: DnsType, qclass: DnsClass, ttl: u32, data: Vec<u8>) -> Self {
-        Self { name: DnsName::new(name.to_string()), qtype, qclass, ttl, length: data.len(), data }
+    pub fn new(name: &str, qtype: DnsType, qclass: DnsClass, ttl: u32, data: RData) -> Self {
+        Self { name: DnsName::new(name.to_string()), qtype, qclass, ttl, data }
     }
 
     pub fn as_buf(&self) -> BytesMut {
         let mut buf = BytesMut::new();
         buf.put(self.name.as_buf());
-        buf.put_u16(self.qtype as u16);
-        buf.put_u16(self.qclass as u16);
+        buf.put_u16(u16::from(self.qtype));
+        buf.put_u16(u16::from(self.qclass));
         buf.put_u32(self.ttl);
-        buf.put_u16(self.length as u16);
-        buf.put_slice(self.data.as_slice());
+        let rdata = self.data.as_buf();
+        buf.put_u16(rdata.len() as u16);
+        buf.put(rdata);
         buf
     }
+
+    /// Writes the record through a message encoder, compressing the owner name and, depending on
+    /// the type, names embedded in RDATA.
+    pub fn write(&self, encoder: &mut DnsEncoder) {
+        encoder.put_name(&self.name);
+        encoder.buf().put_u16(u16::from(self.qtype));
+        encoder.buf().put_u16(u16::from(self.qclass));
+        encoder.buf().put_u32(self.ttl);
+
+        let length_position = encoder.len();
+        encoder.buf().put_u16(0);
+        self.data.write(encoder);
+        let length = encoder.len() - length_position - 2;
+        encoder.patch_u16(length_position, length as u16);
+    }
+
+    /// Parses a resource record starting at `start_index` in a full message buffer and returns it
+    /// along with the number of bytes it occupied.
+    pub fn from_buf(data: &[u8], start_index: usize) -> Result<(Self, usize), DnsError> {
+        let name = DnsName::from_buf(data, start_index)?;
+        let skip = start_index + name.length;
+        let qtype = DnsType::from(read_u16(data, skip)?);
+        let qclass = DnsClass::from(read_u16(data, skip + 2)?);
+        let rdata_length = read_u16(data, skip + 8)? as usize;
+        let length = name.length + 10 + rdata_length;
+
+        // prerequisites and deletions in an update name a whole RRset or name with class ANY or
+        // NONE and no RDATA (RFC 2136 section 2.4)
+        let rdata = match qclass {
+            DnsClass::ANY | DnsClass::NONE if rdata_length == 0 => RData::Unknown(vec![]),
+            _ => RData::from_buf(qtype, data, skip + 10, rdata_length)?,
+        };
+        let answer = Self {
+            qtype,
+            qclass,
+            ttl: read_u32(data, skip + 4)?,
+            data: rdata,
+            name,
+        };
+
+        Ok((answer, length))
+    }
 }
 
-impl From<&[u8]> for DnsAnswer {
-    fn from(data: &[u8]) -> Self {
-        let mut name_parts: Vec<&str> = vec![];
-        let mut to_skip = 0;
-        let mut has_next_part = true;
-        
-        while has_next_part {
-            let part_length = data[to_skip];
-            let part = &data[to_skip + 1..to_skip + 1 + part_length as usize];
-            let name_part = str::from_utf8(part).unwrap();
+/// The record as a master file line: owner, TTL, class, type and RDATA.
+impl fmt::Display for DnsAnswer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.name, self.ttl, self.qclass, self.qtype, self.data
+        )
+    }
+}
 
-            name_parts.push(name_part);
-            
-            match data[to_skip + part_length as usize + 1] {
-                0 => {
-                    has_next_part = false;
-                    to_skip += part_length as usize + 2;
-                },
-                _ => {
-                    to_skip += part_length as usize + 1;
-                }
-            }
-        }
+impl TryFrom<&[u8]> for DnsAnswer {
+    type Error = DnsError;
 
-        Self {
-            name: DnsName::new(name_parts.join(".")),
-            qtype: DnsType::from(u16::from_be_bytes([data[to_skip], data[to_skip + 1]])),
-            qclass: DnsClass::from(u16::from_be_bytes([data[to_skip + 2], data[to_skip + 3]])),
-            ttl: u32::from_be_bytes([data[to_skip + 4], data[to_skip + 5], data[to_skip + 6], data[to_skip + 7]]),
-            length: 4,
-            data: vec![
-                data[to_skip + 10],
-                data[to_skip + 11],
-                data[to_skip + 12],
-                data[to_skip + 13],
-            ],
-        }
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Self::from_buf(data, 0)?.0)
     }
 }
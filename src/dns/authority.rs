@@ -1,9 +1,4 @@
+use super::answer::DnsAnswer;
 
-#[derive(Debug)]
-pub struct DnsAuthority {
-    name: String,
-    qtype: u16,
-    qclass: u16,
-    ttl: u32,
-    data: Vec<u8>,
-}
+/// Authority section records (NS referrals, SOA for negative answers) share the answer format.
+pub type DnsAuthority = DnsAnswer;
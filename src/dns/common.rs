@@ -1,13 +1,58 @@
+use std::{
+    borrow::Cow,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
 use bytes::{BufMut, BytesMut};
 
-#[derive(Debug, Clone, Copy)]
+use crate::error::DnsError;
+
+/// Returns `len` bytes of `data` starting at `offset`, or a truncation error if the buffer is too short.
+pub fn read_bytes(data: &[u8], offset: usize, len: usize) -> Result<&[u8], DnsError> {
+    data.get(offset..offset + len)
+        .ok_or(DnsError::Truncated { offset, needed: len })
+}
+
+pub fn read_u8(data: &[u8], offset: usize) -> Result<u8, DnsError> {
+    Ok(read_bytes(data, offset, 1)?[0])
+}
+
+pub fn read_u16(data: &[u8], offset: usize) -> Result<u16, DnsError> {
+    let bytes = read_bytes(data, offset, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> Result<u32, DnsError> {
+    let bytes = read_bytes(data, offset, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Record types. Values without a variant of their own are carried as `Unknown` (RFC 3597).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum DnsType {
-    A = 1,
-    NS = 2,
-    CNAME = 5,
-    SOA = 6,
-    WKS = 11,
-    PTR = 12,
+    A,
+    NS,
+    CNAME,
+    SOA,
+    WKS,
+    PTR,
+    HINFO,
+    MX,
+    TXT,
+    AAAA,
+    SRV,
+    NAPTR,
+    DNAME,
+    OPT,
+    SSHFP,
+    CAA,
+    // meta-types, only ever asked about (RFC 1995, RFC 5936, RFC 1035 section 3.2.3)
+    IXFR,
+    AXFR,
+    ANY,
+    Unknown(u16),
 }
 
 impl From<u16> for DnsType {
@@ -19,17 +64,124 @@ impl From<u16> for DnsType {
             6 => DnsType::SOA,
             11 => DnsType::WKS,
             12 => DnsType::PTR,
-            _ => unreachable!(),
+            13 => DnsType::HINFO,
+            15 => DnsType::MX,
+            16 => DnsType::TXT,
+            28 => DnsType::AAAA,
+            33 => DnsType::SRV,
+            35 => DnsType::NAPTR,
+            39 => DnsType::DNAME,
+            41 => DnsType::OPT,
+            44 => DnsType::SSHFP,
+            251 => DnsType::IXFR,
+            252 => DnsType::AXFR,
+            255 => DnsType::ANY,
+            257 => DnsType::CAA,
+            _ => DnsType::Unknown(value),
+        }
+    }
+}
+
+impl From<DnsType> for u16 {
+    fn from(value: DnsType) -> Self {
+        match value {
+            DnsType::A => 1,
+            DnsType::NS => 2,
+            DnsType::CNAME => 5,
+            DnsType::SOA => 6,
+            DnsType::WKS => 11,
+            DnsType::PTR => 12,
+            DnsType::HINFO => 13,
+            DnsType::MX => 15,
+            DnsType::TXT => 16,
+            DnsType::AAAA => 28,
+            DnsType::SRV => 33,
+            DnsType::NAPTR => 35,
+            DnsType::DNAME => 39,
+            DnsType::OPT => 41,
+            DnsType::SSHFP => 44,
+            DnsType::IXFR => 251,
+            DnsType::AXFR => 252,
+            DnsType::ANY => 255,
+            DnsType::CAA => 257,
+            DnsType::Unknown(value) => value,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl fmt::Display for DnsType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsType::A => write!(f, "A"),
+            DnsType::NS => write!(f, "NS"),
+            DnsType::CNAME => write!(f, "CNAME"),
+            DnsType::SOA => write!(f, "SOA"),
+            DnsType::WKS => write!(f, "WKS"),
+            DnsType::PTR => write!(f, "PTR"),
+            DnsType::HINFO => write!(f, "HINFO"),
+            DnsType::MX => write!(f, "MX"),
+            DnsType::TXT => write!(f, "TXT"),
+            DnsType::AAAA => write!(f, "AAAA"),
+            DnsType::SRV => write!(f, "SRV"),
+            DnsType::NAPTR => write!(f, "NAPTR"),
+            DnsType::DNAME => write!(f, "DNAME"),
+            DnsType::OPT => write!(f, "OPT"),
+            DnsType::SSHFP => write!(f, "SSHFP"),
+            DnsType::CAA => write!(f, "CAA"),
+            DnsType::IXFR => write!(f, "IXFR"),
+            DnsType::AXFR => write!(f, "AXFR"),
+            DnsType::ANY => write!(f, "ANY"),
+            DnsType::Unknown(value) => write!(f, "TYPE{}", value),
+        }
+    }
+}
+
+/// Accepts mnemonics case-insensitively as well as the RFC 3597 `TYPEnnn` form.
+impl FromStr for DnsType {
+    type Err = DnsError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let upper = value.to_ascii_uppercase();
+        match upper.as_str() {
+            "A" => Ok(DnsType::A),
+            "NS" => Ok(DnsType::NS),
+            "CNAME" => Ok(DnsType::CNAME),
+            "SOA" => Ok(DnsType::SOA),
+            "WKS" => Ok(DnsType::WKS),
+            "PTR" => Ok(DnsType::PTR),
+            "HINFO" => Ok(DnsType::HINFO),
+            "MX" => Ok(DnsType::MX),
+            "TXT" => Ok(DnsType::TXT),
+            "AAAA" => Ok(DnsType::AAAA),
+            "SRV" => Ok(DnsType::SRV),
+            "NAPTR" => Ok(DnsType::NAPTR),
+            "DNAME" => Ok(DnsType::DNAME),
+            "OPT" => Ok(DnsType::OPT),
+            "SSHFP" => Ok(DnsType::SSHFP),
+            "CAA" => Ok(DnsType::CAA),
+            "IXFR" => Ok(DnsType::IXFR),
+            "AXFR" => Ok(DnsType::AXFR),
+            "ANY" => Ok(DnsType::ANY),
+            _ => upper
+                .strip_prefix("TYPE")
+                .and_then(|number| number.parse::<u16>().ok())
+                .map(DnsType::from)
+                .ok_or_else(|| DnsError::InvalidText(value.to_string())),
+        }
+    }
+}
+
+/// Record classes. Values without a variant of their own are carried as `Unknown` (RFC 3597).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum DnsClass {
-    IN = 1,
-    CS = 2,
-    CH = 3,
-    HS = 4,
+    IN,
+    CS,
+    CH,
+    HS,
+    // only in dynamic updates, marking what to check or delete (RFC 2136 section 2.4)
+    NONE,
+    ANY,
+    Unknown(u16),
 }
 
 impl From<u16> for DnsClass {
@@ -39,7 +191,59 @@ impl From<u16> for DnsClass {
             2 => DnsClass::CS,
             3 => DnsClass::CH,
             4 => DnsClass::HS,
-            _ => unreachable!(),
+            254 => DnsClass::NONE,
+            255 => DnsClass::ANY,
+            _ => DnsClass::Unknown(value),
+        }
+    }
+}
+
+impl From<DnsClass> for u16 {
+    fn from(value: DnsClass) -> Self {
+        match value {
+            DnsClass::IN => 1,
+            DnsClass::CS => 2,
+            DnsClass::CH => 3,
+            DnsClass::HS => 4,
+            DnsClass::NONE => 254,
+            DnsClass::ANY => 255,
+            DnsClass::Unknown(value) => value,
+        }
+    }
+}
+
+impl fmt::Display for DnsClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsClass::IN => write!(f, "IN"),
+            DnsClass::CS => write!(f, "CS"),
+            DnsClass::CH => write!(f, "CH"),
+            DnsClass::HS => write!(f, "HS"),
+            DnsClass::NONE => write!(f, "NONE"),
+            DnsClass::ANY => write!(f, "ANY"),
+            DnsClass::Unknown(value) => write!(f, "CLASS{}", value),
+        }
+    }
+}
+
+/// Accepts mnemonics case-insensitively as well as the RFC 3597 `CLASSnnn` form.
+impl FromStr for DnsClass {
+    type Err = DnsError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let upper = value.to_ascii_uppercase();
+        match upper.as_str() {
+            "IN" => Ok(DnsClass::IN),
+            "CS" => Ok(DnsClass::CS),
+            "CH" => Ok(DnsClass::CH),
+            "HS" => Ok(DnsClass::HS),
+            "NONE" => Ok(DnsClass::NONE),
+            "ANY" => Ok(DnsClass::ANY),
+            _ => upper
+                .strip_prefix("CLASS")
+                .and_then(|number| number.parse::<u16>().ok())
+                .map(DnsClass::from)
+                .ok_or_else(|| DnsError::InvalidText(value.to_string())),
         }
     }
 }
@@ -51,10 +255,166 @@ pub struct DnsName {
     pub length: usize,
 }
 
+/// Presentation form of the name, always fully qualified.
+impl fmt::Display for DnsName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.trimmed() {
+            "" => write!(f, "."),
+            name => write!(f, "{}.", name),
+        }
+    }
+}
+
+// Names compare case-insensitively and without regard to a trailing dot (RFC 4343).
+impl PartialEq for DnsName {
+    fn eq(&self, other: &Self) -> bool {
+        self.trimmed().eq_ignore_ascii_case(other.trimmed())
+    }
+}
+
+impl Eq for DnsName {}
+
+impl Hash for DnsName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.trimmed().to_ascii_lowercase().hash(state);
+    }
+}
+
+/// Maximum encoded length of a name, counting length octets and the root label.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Upper bound on compression pointers followed while reading a single name.
+const MAX_POINTER_HOPS: usize = 127;
+
+/// Parses a TTL or other time value in seconds, also accepting the common `1h30m` style with
+/// w, d, h, m and s units.
+pub fn parse_ttl(text: &str) -> Result<u32, DnsError> {
+    let invalid = || DnsError::InvalidText(text.to_string());
+    if let Ok(seconds) = text.parse() {
+        return Ok(seconds);
+    }
+
+    let mut total: u32 = 0;
+    let mut number: Option<u32> = None;
+    for c in text.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            number = Some(
+                number
+                    .unwrap_or(0)
+                    .checked_mul(10)
+                    .and_then(|n| n.checked_add(digit))
+                    .ok_or_else(invalid)?,
+            );
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            'w' => 604800,
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let seconds = number.take().ok_or_else(invalid)?.checked_mul(unit);
+        total = seconds
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(invalid)?;
+    }
+
+    match number {
+        // a trailing bare number counts as seconds, as in 1m30
+        Some(seconds) => total.checked_add(seconds).ok_or_else(invalid),
+        None if text.is_empty() => Err(invalid()),
+        None => Ok(total),
+    }
+}
+
+/// A label read off the wire in presentation form. Labels are arbitrary octets (RFC 2181 section
+/// 11), so dots, backslashes and characters master files treat specially are escaped with a
+/// backslash, and octets outside printable ASCII become `\DDD` (RFC 1035 section 5.1).
+pub fn escape_label(label: &[u8]) -> String {
+    let mut text = String::with_capacity(label.len());
+    for &octet in label {
+        match octet {
+            b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                text.push('\\');
+                text.push(octet as char);
+            }
+            0x21..=0x7E => text.push(octet as char),
+            _ => text.push_str(&format!("\\{:03}", octet)),
+        }
+    }
+    text
+}
+
+/// The octets of a label in presentation form, undoing `escape_label`.
+pub fn unescape_label(label: &str) -> Cow<'_, [u8]> {
+    if !label.contains('\\') {
+        return Cow::Borrowed(label.as_bytes());
+    }
+    Cow::Owned(decode_label(label).unwrap_or_else(|| label.as_bytes().to_vec()))
+}
+
+/// Decodes `\X` and `\DDD` escapes in a label, or `None` if one is malformed.
+fn decode_label(label: &str) -> Option<Vec<u8>> {
+    let text = label.as_bytes();
+    let mut octets = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] != b'\\' {
+            octets.push(text[i]);
+            i += 1;
+            continue;
+        }
+        match text.get(i + 1..i + 4) {
+            Some(digits) if digits.iter().all(u8::is_ascii_digit) => {
+                octets.push(std::str::from_utf8(digits).ok()?.parse().ok()?);
+                i += 4;
+            }
+            _ => {
+                octets.push(*text.get(i + 1)?);
+                i += 2;
+            }
+        }
+    }
+    Some(octets)
+}
+
+/// Splits a name in presentation form at the dots that aren't escaped, keeping empty labels.
+fn split_labels(name: &str) -> Vec<&str> {
+    let mut labels = vec![];
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, byte) in name.bytes().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b'.' => {
+                labels.push(&name[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    labels.push(&name[start..]);
+    labels
+}
+
+/// `name` without its trailing root dot, or `None` if it doesn't end in one. An escaped dot
+/// belongs to the last label instead.
+fn strip_root(name: &str) -> Option<&str> {
+    let rest = name.strip_suffix('.')?;
+    let backslashes = rest.bytes().rev().take_while(|&byte| byte == b'\\').count();
+    (backslashes % 2 == 0).then_some(rest)
+}
+
 impl DnsName {
     pub fn new(name: String) -> Self {
-        // total length is always 1 byte for each part + 1 byte for the end of the name + characters length in each part
-        let length = name.split('.').fold(1, |acc, part| acc + part.len() + 1);
+        // total length is always 1 byte for each part + 1 byte for the end of the name + octets in each part
+        let length = split_labels(&name)
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .fold(1, |acc, part| acc + unescape_label(part).len() + 1);
 
         Self {
             name,
@@ -63,52 +423,237 @@ impl DnsName {
         }
     }
 
+    /// Iterates over the labels of the name in presentation form, skipping the empty root label.
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &str> {
+        split_labels(&self.name)
+            .into_iter()
+            .filter(|part| !part.is_empty())
+    }
+
+    /// The name without a trailing root dot.
+    fn trimmed(&self) -> &str {
+        strip_root(&self.name).unwrap_or(&self.name)
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels().count()
+    }
+
+    /// Whether this name equals `ancestor` or lies below it; every name is under the root.
+    pub fn is_subdomain_of(&self, ancestor: &DnsName) -> bool {
+        let mut labels = self.labels().rev();
+        ancestor
+            .labels()
+            .rev()
+            .all(|label| labels.next().is_some_and(|own| own.eq_ignore_ascii_case(label)))
+    }
+
+    /// The name with its leftmost label removed, or `None` for the root.
+    pub fn parent(&self) -> Option<DnsName> {
+        let mut labels = self.labels();
+        labels.next()?;
+        Some(DnsName::new(labels.collect::<Vec<_>>().join(".")))
+    }
+
+    /// This name with `suffix` swapped for `replacement`, as a DNAME substitutes names (RFC 6672
+    /// section 2.2). `None` if the name isn't below `suffix` or the result would be too long.
+    pub fn replace_suffix(&self, suffix: &DnsName, replacement: &DnsName) -> Option<DnsName> {
+        if !self.is_subdomain_of(suffix) {
+            return None;
+        }
+        let prefix: Vec<&str> = self
+            .labels()
+            .take(self.label_count() - suffix.label_count())
+            .collect();
+        let labels: Vec<&str> = prefix.into_iter().chain(replacement.labels()).collect();
+        let name = DnsName::new(labels.join("."));
+        (name.length <= MAX_NAME_LENGTH).then_some(name)
+    }
+
+    /// Whether the name can go in a message: no empty labels, none longer than 63 octets, and at
+    /// most 255 octets in all (RFC 1035 section 2.3.4). Names parsed from text or off the wire
+    /// always are, `new` doesn't check.
+    pub fn is_valid(&self) -> bool {
+        let name = self.trimmed();
+        let labels_valid = name.is_empty()
+            || split_labels(name)
+                .into_iter()
+                .all(|label| (1..=63).contains(&unescape_label(label).len()));
+        labels_valid && self.length <= MAX_NAME_LENGTH
+    }
+
+    /// Parses a name in master file presentation format. `@` stands for `origin`, and names not
+    /// ending in a dot are relative to it.
+    pub fn from_text(text: &str, origin: &DnsName) -> Result<Self, DnsError> {
+        let invalid = || DnsError::InvalidText(text.to_string());
+        let name = match text {
+            "" => return Err(invalid()),
+            "@" => origin.trimmed().to_string(),
+            "." => String::new(),
+            _ => match strip_root(text) {
+                Some(absolute) => absolute.to_string(),
+                None if origin.labels().next().is_none() => text.to_string(),
+                None => format!("{}.{}", text, origin.trimmed()),
+            },
+        };
+
+        // escapes are rewritten the way names off the wire have them, so both compare equal
+        let mut labels = vec![];
+        if !name.is_empty() {
+            for label in split_labels(&name) {
+                let octets = decode_label(label)
+                    .filter(|octets| !octets.is_empty() && octets.len() <= 63)
+                    .ok_or_else(invalid)?;
+                labels.push(escape_label(&octets));
+            }
+        }
+        let name = DnsName::new(labels.join("."));
+        if name.length > MAX_NAME_LENGTH {
+            return Err(invalid());
+        }
+        Ok(name)
+    }
+
     pub fn as_buf(&self) -> BytesMut {
         let mut buf = BytesMut::new();
         // process name parts
-        self.name.split('.').for_each(|part| {
+        self.labels().for_each(|part| {
+            let part = unescape_label(part);
             // put the length of the current part
             buf.put_u8(part.len() as u8);
             // put the current part
-            buf.put(part.as_bytes());
+            buf.put(&part[..]);
         });
         // process end of name, 0 byte
         buf.put_u8(0);
         buf
     }
 
-    /// Reads a DNS name from a buffer and returns the name when reading is done along with an optional offset pointer if encountered.
-    /// This method assumes the name starts at the beginning of the buffer.
-    pub fn read(data: &[u8]) -> Self {
+    /// Reads a DNS name starting at `start_index` in a full message buffer, following compression
+    /// pointers (including chained ones) against the same buffer. The returned `length` is the
+    /// number of bytes the name occupies at `start_index` and `offset` is the target of the first
+    /// pointer followed, if any.
+    pub fn from_buf(data: &[u8], start_index: usize) -> Result<Self, DnsError> {
         let mut name_parts: Vec<String> = vec![];
-        let mut to_skip = 0;
-        let mut has_next_part = true;
+        let mut name_length = 1;
+        let mut position = start_index;
+        let mut segment_start = start_index;
+        let mut length = None;
+        let mut first_pointer = None;
+        let mut hops = 0;
 
-        while has_next_part {
-            let part_length = data[to_skip];
+        loop {
+            let part_length = read_u8(data, position)?;
 
             if part_length & 0b11000000 == 0b11000000 {
                 // Handle compression pointer
-                let offset =
-                    ((part_length & 0b00111111) as usize) << 8 | data[to_skip + 1] as usize;
-                
-                return DnsName {
-                    name: name_parts.join("."), 
-                    offset: Some(offset), 
-                    length: to_skip 
+                let target = (read_u16(data, position)? & 0x3FFF) as usize;
+
+                // pointers must refer to earlier data, anything else is malformed
+                if target >= position {
+                    return Err(DnsError::BadPointer { offset: position, target });
                 }
+                // a pointer back into the labels just read would never terminate
+                hops += 1;
+                if target >= segment_start || hops > MAX_POINTER_HOPS {
+                    return Err(DnsError::PointerLoop { offset: position });
+                }
+
+                length.get_or_insert_with(|| position + 2 - start_index);
+                first_pointer.get_or_insert(target);
+                position = target;
+                segment_start = target;
+            } else if part_length & 0b11000000 != 0 {
+                // 0b01 and 0b10 prefixes are reserved
+                return Err(DnsError::BadLabel { offset: position });
             } else if part_length == 0 {
                 // End of name
-                has_next_part = false;
-                to_skip += 1;
+                position += 1;
+                break;
             } else {
                 // Regular label
-                let part = &data[to_skip + 1..to_skip + 1 + part_length as usize];
-                name_parts.push(String::from_utf8(part.to_vec()).unwrap());
-                to_skip += part_length as usize + 1;
+                name_length += part_length as usize + 1;
+                if name_length > MAX_NAME_LENGTH {
+                    return Err(DnsError::NameTooLong { offset: start_index });
+                }
+
+                let part = read_bytes(data, position + 1, part_length as usize)?;
+                name_parts.push(escape_label(part));
+                position += part_length as usize + 1;
             }
         }
 
-        DnsName { name: name_parts.join("."), offset: None, length: to_skip }
+        Ok(DnsName {
+            name: name_parts.join("."),
+            offset: first_pointer,
+            length: length.unwrap_or_else(|| position - start_index),
+        })
+    }
+
+    /// Reads a DNS name from a buffer that starts with the name. Compression pointers are resolved
+    /// against the same buffer, so it should be the whole message when the name may be compressed.
+    pub fn read(data: &[u8]) -> Result<Self, DnsError> {
+        Self::from_buf(data, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DnsName;
+    use crate::error::DnsError;
+
+    #[test]
+    fn test_dns_name_follows_chained_pointers() {
+        let mut data = vec![0u8; 12];
+        // 12: example.com
+        data.extend(b"\x07example\x03com\x00");
+        // 25: www -> 12
+        data.extend(b"\x03www\xC0\x0C");
+        // 31: a -> 25
+        data.extend(b"\x01a\xC0\x19");
+
+        let name = DnsName::from_buf(&data, 31).unwrap();
+        assert_eq!(name.name, "a.www.example.com");
+        assert_eq!(name.length, 4);
+        assert_eq!(name.offset, Some(25));
+
+        let root = DnsName::from_buf(&[0], 0).unwrap();
+        assert_eq!(root.name, "");
+        assert_eq!(root.as_buf().as_ref(), &[0]);
+    }
+
+    #[test]
+    fn test_dns_name_keeps_any_label_octets() {
+        // a label with a non-UTF-8 octet, a dot and a space, then "example"
+        let wire = b"\x05\xff.a b\x07example\x00";
+        let name = DnsName::from_buf(wire, 0).unwrap();
+        assert_eq!(name.name, "\\255\\.a\\032b.example");
+        assert_eq!(name.label_count(), 2);
+        assert_eq!(name.length, wire.len());
+        assert_eq!(&name.as_buf()[..], wire);
+        assert_eq!(name.to_string(), "\\255\\.a\\032b.example.");
+
+        // the presentation form parses back to the same name, however it escapes the octets
+        let root = DnsName::new(String::new());
+        for text in ["\\255\\.a\\032b.example.", "\\255\\046\\a\\ b.example."] {
+            assert_eq!(DnsName::from_text(text, &root).unwrap(), name);
+        }
+        assert!(DnsName::from_text("\\256.example.", &root).is_err());
+        assert!(DnsName::from_text("a\\", &root).is_err());
+    }
+
+    #[test]
+    fn test_dns_name_rejects_bad_pointers() {
+        // points at itself
+        let data = [0u8, 0, 0xC0, 0x02];
+        assert_eq!(DnsName::from_buf(&data, 2).unwrap_err(), DnsError::BadPointer { offset: 2, target: 2 });
+
+        // points back at the start of the name it terminates
+        let data = [1u8, b'a', 0xC0, 0x00];
+        assert_eq!(DnsName::from_buf(&data, 0).unwrap_err(), DnsError::PointerLoop { offset: 2 });
+
+        // 128 labels of length 1 exceed 255 octets
+        let data: Vec<u8> = (0..128).flat_map(|_| [1u8, b'x']).chain([0]).collect();
+        assert_eq!(DnsName::from_buf(&data, 0).unwrap_err(), DnsError::NameTooLong { offset: 0 });
     }
 }
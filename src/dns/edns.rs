@@ -0,0 +1,154 @@
+use bytes::{BufMut, BytesMut};
+
+use crate::error::DnsError;
+
+use super::{
+    additional::DnsAdditional,
+    common::{read_bytes, read_u16, DnsClass, DnsName, DnsType},
+    rdata::RData,
+};
+
+/// Payload size assumed for clients that don't advertise one (RFC 1035 section 4.2.1).
+pub const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 512;
+
+/// DO bit in the EDNS flags, set by clients that want DNSSEC records (RFC 3225).
+pub const EDNS_FLAG_DO: u16 = 0x8000;
+
+/// Extended RCODE returned when a client uses an EDNS version we don't implement.
+pub const EXTENDED_RCODE_BADVERS: u16 = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// EDNS(0) parameters carried by the OPT pseudo-record (RFC 6891).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    // requestor's UDP payload size, carried in the CLASS field
+    pub udp_payload_size: u16,
+    // upper 8 bits of the 12-bit extended RCODE
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub flags: u16,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Self {
+        Self {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            flags: 0,
+            options: vec![],
+        }
+    }
+
+    pub fn dnssec_ok(&self) -> bool {
+        self.flags & EDNS_FLAG_DO != 0
+    }
+
+    /// Payload size the requestor can receive; values below 512 are treated as 512.
+    pub fn max_payload_size(&self) -> usize {
+        self.udp_payload_size.max(DEFAULT_UDP_PAYLOAD_SIZE) as usize
+    }
+
+    /// Reads EDNS parameters out of an OPT record found in the additional section.
+    pub fn from_record(record: &DnsAdditional) -> Result<Self, DnsError> {
+        // the OPT owner name must be the root
+        if record.qtype != DnsType::OPT || !record.name.name.is_empty() {
+            return Err(DnsError::InvalidOpt);
+        }
+        let RData::Unknown(data) = &record.data else {
+            return Err(DnsError::InvalidOpt);
+        };
+
+        let mut options = vec![];
+        let mut skip = 0;
+        while skip < data.len() {
+            let code = read_u16(data, skip)?;
+            let length = read_u16(data, skip + 2)? as usize;
+            options.push(EdnsOption {
+                code,
+                data: read_bytes(data, skip + 4, length)?.to_vec(),
+            });
+            skip += 4 + length;
+        }
+
+        Ok(Self {
+            udp_payload_size: u16::from(record.qclass),
+            extended_rcode: (record.ttl >> 24) as u8,
+            version: (record.ttl >> 16) as u8,
+            flags: record.ttl as u16,
+            options,
+        })
+    }
+
+    /// Builds the OPT record to place at the end of the additional section.
+    pub fn to_record(&self) -> DnsAdditional {
+        let mut data = BytesMut::new();
+        self.options.iter().for_each(|option| {
+            data.put_u16(option.code);
+            data.put_u16(option.data.len() as u16);
+            data.put_slice(&option.data);
+        });
+
+        DnsAdditional {
+            name: DnsName::new(String::new()),
+            qtype: DnsType::OPT,
+            qclass: DnsClass::from(self.udp_payload_size),
+            ttl: (self.extended_rcode as u32) << 24
+                | (self.version as u32) << 16
+                | self.flags as u32,
+            data: RData::Unknown(data.to_vec()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Edns, EdnsOption, EDNS_FLAG_DO};
+    use crate::dns::{
+        common::DnsType, edns::DEFAULT_UDP_PAYLOAD_SIZE, header::DnsHeader, message::DnsMessage,
+    };
+
+    #[test]
+    fn test_edns_round_trips_through_opt_record() {
+        let edns = Edns {
+            udp_payload_size: 1232,
+            extended_rcode: 1,
+            version: 0,
+            flags: EDNS_FLAG_DO,
+            options: vec![EdnsOption {
+                code: 10,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }],
+        };
+
+        let record = edns.to_record();
+        assert_eq!(record.qtype, DnsType::OPT);
+        assert_eq!(record.ttl, 0x0100_8000);
+
+        let parsed = Edns::from_record(&record).unwrap();
+        assert_eq!(parsed, edns);
+        assert!(parsed.dnssec_ok());
+        assert_eq!(
+            Edns::new(100).max_payload_size(),
+            DEFAULT_UDP_PAYLOAD_SIZE as usize
+        );
+
+        // the OPT record is pulled out of the additional section when parsing a message
+        let header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        let mut message = DnsMessage::new(header, vec![], vec![], vec![], vec![]);
+        message.edns = Some(edns.clone());
+        let buf = message.as_buf();
+        assert_eq!(&buf[10..12], &[0, 1]);
+
+        let parsed = DnsMessage::try_from(&buf[..]).unwrap();
+        assert!(parsed.additional.is_empty());
+        assert_eq!(parsed.edns, Some(edns));
+        assert_eq!(parsed.max_udp_response_size(), 1232);
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use bytes::{BufMut, BytesMut};
+
+use super::common::{unescape_label, DnsName};
+
+/// Pointers only have 14 bits for the offset, names written past this can't be pointed at.
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// Serializes a message while remembering where each name suffix was written, so later
+/// occurrences can be replaced by compression pointers (RFC 1035 section 4.1.4).
+#[derive(Debug)]
+pub struct DnsEncoder {
+    buf: BytesMut,
+    compress: bool,
+    // suffix (e.g. "example.com") -> offset of its first label in `buf`
+    names: HashMap<String, usize>,
+}
+
+impl DnsEncoder {
+    /// Creates an encoder. With `compress` disabled every name is written in full, which is what
+    /// canonical and DNSSEC forms require.
+    pub fn new(compress: bool) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(512),
+            compress,
+            names: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn buf(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    pub fn finish(self) -> BytesMut {
+        self.buf
+    }
+
+    /// Overwrites two previously written bytes, used to fill in lengths once they are known.
+    pub fn patch_u16(&mut self, position: usize, value: u16) {
+        self.buf[position..position + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// Writes a name, replacing the longest suffix already present in the message with a pointer.
+    pub fn put_name(&mut self, name: &DnsName) {
+        if !self.compress {
+            self.buf.put(name.as_buf());
+            return;
+        }
+
+        let labels: Vec<&str> = name.labels().collect();
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".");
+            if let Some(&offset) = self.names.get(&suffix) {
+                self.buf.put_u16(0xC000 | offset as u16);
+                return;
+            }
+
+            if self.buf.len() <= MAX_POINTER_OFFSET {
+                self.names.insert(suffix, self.buf.len());
+            }
+            let label = unescape_label(labels[i]);
+            self.buf.put_u8(label.len() as u8);
+            self.buf.put(&label[..]);
+        }
+        // process end of name, 0 byte
+        self.buf.put_u8(0);
+    }
+}
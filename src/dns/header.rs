@@ -2,6 +2,8 @@ extern crate bit;
 use bit::BitIndex;
 use bytes::{BufMut, BytesMut};
 
+use crate::error::DnsError;
+
 // QR - Query/Response
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum DnsHeaderQR {
@@ -18,27 +20,42 @@ impl From<u8> for DnsHeaderQR {
     }
 }
 
-// OPCODE - Operation Code
+// OPCODE - Operation Code. Values without a variant of their own are carried as `Unknown`.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum DnsHeaderOpcode {
-    Query = 0,
-    IQuery = 1,
-    Status = 2,
-    Notify = 3,
-    Update = 4,
-    Dnssec = 5,
+    Query,
+    IQuery,
+    Status,
+    Notify,
+    Update,
+    Dnssec,
+    Unknown(u8),
 }
 
 impl From<u8> for DnsHeaderOpcode {
     fn from(data: u8) -> Self {
-        match data.bit_range(3..6) {
+        match data.bit_range(3..7) {
             0 => DnsHeaderOpcode::Query,
             1 => DnsHeaderOpcode::IQuery,
             2 => DnsHeaderOpcode::Status,
             3 => DnsHeaderOpcode::Notify,
             4 => DnsHeaderOpcode::Update,
             5 => DnsHeaderOpcode::Dnssec,
-            value => panic!("Invalid OPCODE value: {}", value),
+            value => DnsHeaderOpcode::Unknown(value),
+        }
+    }
+}
+
+impl From<DnsHeaderOpcode> for u8 {
+    fn from(value: DnsHeaderOpcode) -> Self {
+        match value {
+            DnsHeaderOpcode::Query => 0,
+            DnsHeaderOpcode::IQuery => 1,
+            DnsHeaderOpcode::Status => 2,
+            DnsHeaderOpcode::Notify => 3,
+            DnsHeaderOpcode::Update => 4,
+            DnsHeaderOpcode::Dnssec => 5,
+            DnsHeaderOpcode::Unknown(value) => value,
         }
     }
 }
@@ -107,42 +124,107 @@ impl From<u8> for DnsHeaderRA {
     }
 }
 
-// Z - Reserved
+// Z - Reserved, must be zero but kept as received
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum DnsHeaderZ {
     Reserved = 0,
+    Set = 1,
 }
 
 impl From<u8> for DnsHeaderZ {
     fn from(data: u8) -> Self {
-        match data.bit_range(4..6) {
-            0 => DnsHeaderZ::Reserved,
-            value => panic!("Invalid Z value: {}", value),
+        match data.bit(6) {
+            false => DnsHeaderZ::Reserved,
+            true => DnsHeaderZ::Set,
         }
     }
 }
 
-// RCODE - Response Code
+// AD - Authentic Data (RFC 4035 section 3.2.3)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DnsHeaderAD {
+    NotAuthenticated = 0,
+    Authenticated = 1,
+}
+
+impl From<u8> for DnsHeaderAD {
+    fn from(data: u8) -> Self {
+        match data.bit(5) {
+            false => DnsHeaderAD::NotAuthenticated,
+            true => DnsHeaderAD::Authenticated,
+        }
+    }
+}
+
+// CD - Checking Disabled (RFC 4035 section 3.2.2)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DnsHeaderCD {
+    CheckingEnabled = 0,
+    CheckingDisabled = 1,
+}
+
+impl From<u8> for DnsHeaderCD {
+    fn from(data: u8) -> Self {
+        match data.bit(4) {
+            false => DnsHeaderCD::CheckingEnabled,
+            true => DnsHeaderCD::CheckingDisabled,
+        }
+    }
+}
+
+// RCODE - Response Code. Values without a variant of their own are carried as `Unknown`.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum DnsHeaderRcode {
-    NoError = 0,
-    FormatError = 1,
-    ServerFailure = 2,
-    NameError = 3,
-    NotImplemented = 4,
-    Refused = 5,
+    NoError,
+    FormatError,
+    ServerFailure,
+    NameError,
+    NotImplemented,
+    Refused,
+    // a name substituted through a DNAME is too long to exist (RFC 6672 section 2.2)
+    YXDomain,
+    // the rest answer dynamic updates (RFC 2136 section 2.2)
+    YXRRSet,
+    NXRRSet,
+    NotAuth,
+    NotZone,
+    Unknown(u8),
 }
 
 impl From<u8> for DnsHeaderRcode {
     fn from(data: u8) -> Self {
-        match data.bit_range(0..3) {
+        match data.bit_range(0..4) {
             0 => DnsHeaderRcode::NoError,
             1 => DnsHeaderRcode::FormatError,
             2 => DnsHeaderRcode::ServerFailure,
             3 => DnsHeaderRcode::NameError,
             4 => DnsHeaderRcode::NotImplemented,
             5 => DnsHeaderRcode::Refused,
-            value => panic!("Invalid RCODE value: {}", value),
+            6 => DnsHeaderRcode::YXDomain,
+            7 => DnsHeaderRcode::YXRRSet,
+            8 => DnsHeaderRcode::NXRRSet,
+            9 => DnsHeaderRcode::NotAuth,
+            10 => DnsHeaderRcode::NotZone,
+            value => DnsHeaderRcode::Unknown(value),
+        }
+    }
+}
+
+impl From<DnsHeaderRcode> for u8 {
+    fn from(value: DnsHeaderRcode) -> Self {
+        match value {
+            DnsHeaderRcode::NoError => 0,
+            DnsHeaderRcode::FormatError => 1,
+            DnsHeaderRcode::ServerFailure => 2,
+            DnsHeaderRcode::NameError => 3,
+            DnsHeaderRcode::NotImplemented => 4,
+            DnsHeaderRcode::Refused => 5,
+            DnsHeaderRcode::YXDomain => 6,
+            DnsHeaderRcode::YXRRSet => 7,
+            DnsHeaderRcode::NXRRSet => 8,
+            DnsHeaderRcode::NotAuth => 9,
+            DnsHeaderRcode::NotZone => 10,
+            DnsHeaderRcode::Unknown(value) => value,
         }
     }
 }
@@ -164,8 +246,12 @@ pub struct DnsHeader {
     pub recursion_desired: DnsHeaderRD,
     // 1-bit recursion available flag
     pub recursion_available: DnsHeaderRA,
-    // 3-bit reserved
+    // 1-bit reserved
     pub z: DnsHeaderZ,
+    // 1-bit authentic data flag
+    pub authentic_data: DnsHeaderAD,
+    // 1-bit checking disabled flag
+    pub checking_disabled: DnsHeaderCD,
     // 4-bit response code
     pub rcode: DnsHeaderRcode,
     // 16-bit question count
@@ -187,16 +273,18 @@ impl DnsHeader {
         // Byte 2: QR (1 bit) | OPCODE (4 bits) | AA (1 bit) | TC (1 bit) | RD (1 bit)
         buf.put_u8(
             (self.query_response as u8) << 7 |
-            (self.opcode as u8) << 3 |
+            u8::from(self.opcode) << 3 |
             (self.authoritative_answer as u8) << 2 |
             (self.truncation as u8) << 1 |
             (self.recursion_desired as u8)
         );
-        // Byte 3: RA (1 bit) | Z (3 bits) | RCODE (4 bits)
+        // Byte 3: RA (1 bit) | Z (1 bit) | AD (1 bit) | CD (1 bit) | RCODE (4 bits)
         buf.put_u8(
             (self.recursion_available as u8) << 7 |
-            (self.z as u8) << 4 |
-            (self.rcode as u8)
+            (self.z as u8) << 6 |
+            (self.authentic_data as u8) << 5 |
+            (self.checking_disabled as u8) << 4 |
+            u8::from(self.rcode)
         );
         // Bytes 4-5: QDCOUNT (16 bits)
         buf.put_u16(self.question_count);
@@ -211,9 +299,15 @@ impl DnsHeader {
     }
 }
 
-impl From<&[u8]> for DnsHeader {
-    fn from(data: &[u8]) -> Self {
-        Self {
+impl TryFrom<&[u8]> for DnsHeader {
+    type Error = DnsError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() < 12 {
+            return Err(DnsError::Truncated { offset: 0, needed: 12 });
+        }
+
+        Ok(Self {
             id: u16::from_be_bytes([data[0], data[1]]),
             query_response: DnsHeaderQR::from(data[2]),
             opcode: DnsHeaderOpcode::from(data[2]),
@@ -222,11 +316,13 @@ impl From<&[u8]> for DnsHeader {
             recursion_desired: DnsHeaderRD::from(data[2]),
             recursion_available: DnsHeaderRA::from(data[3]),
             z: DnsHeaderZ::from(data[3]),
+            authentic_data: DnsHeaderAD::from(data[3]),
+            checking_disabled: DnsHeaderCD::from(data[3]),
             rcode: DnsHeaderRcode::from(data[3]),
             question_count: u16::from_be_bytes([data[4], data[5]]),
             answer_count: u16::from_be_bytes([data[6], data[7]]),
             authority_count: u16::from_be_bytes([data[8], data[9]]),
             additional_count: u16::from_be_bytes([data[10], data[11]]),
-        }
+        })
     }
 }
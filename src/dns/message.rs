@@ -1,10 +1,21 @@
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
+
+use crate::error::DnsError;
 
 use super::{
-    additional::DnsAdditional, answer::DnsAnswer, authority::DnsAuthority, header::*,
+    additional::DnsAdditional,
+    answer::DnsAnswer,
+    authority::DnsAuthority,
+    common::DnsType,
+    edns::{Edns, DEFAULT_UDP_PAYLOAD_SIZE},
+    encoder::DnsEncoder,
+    header::*,
     question::DnsQuestion,
 };
 
+/// Largest message that fits in a TCP length prefix or a UDP datagram.
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+
 #[derive(Debug)]
 pub struct DnsMessage {
     pub header: DnsHeader,
@@ -12,6 +23,8 @@ pub struct DnsMessage {
     pub answers: Vec<DnsAnswer>,
     pub authorities: Vec<DnsAuthority>,
     pub additional: Vec<DnsAdditional>,
+    // OPT pseudo-record, kept out of `additional` and written as its last record
+    pub edns: Option<Edns>,
 }
 
 impl DnsMessage {
@@ -28,135 +41,268 @@ impl DnsMessage {
             answers,
             authorities,
             additional,
+            edns: None,
         }
     }
 
-    pub fn new_from_received_message(received_message: &DnsMessage) -> Self {
-        let answers: Vec<DnsAnswer> = received_message
-            .questions
-            .iter()
-            .map(|question| {
-                DnsAnswer::new(
-                    &question.name.name,
-                    question.qtype,
-                    question.qclass,
-                    60,
-                    vec![8, 8, 8, 8],
-                )
-            })
-            .collect();
-
-        let header = DnsHeader {
-            id: received_message.header.id,
-            query_response: DnsHeaderQR::Reply,
-            opcode: received_message.header.opcode,
-            authoritative_answer: DnsHeaderAA::NonAuthoritative,
-            truncation: DnsHeaderTC::NotTruncated,
-            recursion_desired: received_message.header.recursion_desired,
-            recursion_available: DnsHeaderRA::RecursionAvailable,
-            z: DnsHeaderZ::Reserved,
-            rcode: match received_message.header.opcode {
-                DnsHeaderOpcode::Query => DnsHeaderRcode::NoError,
-                _ => DnsHeaderRcode::NotImplemented,
-            },
-            question_count: received_message.questions.len() as u16,
-            answer_count: answers.len() as u16,
-            authority_count: 0,
-            additional_count: 0,
-        };
-        
-        Self::new(
-            header,
-            received_message.questions.clone(),
-            answers,
-            vec![],
-            vec![],
-        )
+    /// Serializes the message with name compression.
+    pub fn as_buf(&self) -> BytesMut {
+        self.encode(true)
     }
 
-    pub fn response(&self, received_message: &DnsMessage) -> BytesMut {
-        let mut response: BytesMut = BytesMut::with_capacity(512);
+    /// Serializes the message, optionally leaving every name uncompressed as canonical and DNSSEC
+    /// forms require. Section counts in the header are taken from the sections themselves.
+    pub fn encode(&self, compress: bool) -> BytesMut {
+        let mut header = self.header.clone();
+        header.question_count = self.questions.len() as u16;
+        header.answer_count = self.answers.len() as u16;
+        header.authority_count = self.authorities.len() as u16;
+        header.additional_count = (self.additional.len() + self.edns.iter().len()) as u16;
 
-        let header_buf = self.header.as_buf();
-        response.extend(header_buf);
-
-        received_message.questions.iter().for_each(|question| {
-            response.extend(question.as_buf());
+        let mut encoder = DnsEncoder::new(compress);
+        encoder.buf().extend(header.as_buf());
+        self.questions.iter().for_each(|question| {
+            question.write(&mut encoder);
         });
+        self.answers
+            .iter()
+            .chain(self.authorities.iter())
+            .chain(self.additional.iter())
+            .chain(self.edns.as_ref().map(Edns::to_record).iter())
+            .for_each(|record| {
+                record.write(&mut encoder);
+            });
+        encoder.finish()
+    }
 
-        self.answers.iter().for_each(|answer| {
-            response.extend(answer.as_buf());
-        });
+    /// Serializes the message within `max_size` bytes. Whole RRsets are dropped from the end of
+    /// the additional, then authority, then answer section until it fits; TC is set only if
+    /// answers had to go (RFC 2181 section 9). The OPT record is always kept.
+    pub fn truncate_to(&mut self, max_size: usize) -> BytesMut {
+        let mut buf = self.as_buf();
 
-        response
-    }
+        for section in 0..3 {
+            while buf.len() > max_size {
+                let records = match section {
+                    0 => &mut self.additional,
+                    1 => &mut self.authorities,
+                    _ => &mut self.answers,
+                };
+                let Some(last) = records.last() else {
+                    break;
+                };
+
+                let (name, qtype, qclass) = (last.name.clone(), last.qtype, last.qclass);
+                records.retain(|record| {
+                    record.name != name || record.qtype != qtype || record.qclass != qclass
+                });
+                if section == 2 {
+                    self.header.truncation = DnsHeaderTC::Truncated;
+                }
+                buf = self.as_buf();
+            }
+        }
 
-    pub fn as_buf(&self) -> BytesMut {
-        let mut buf = BytesMut::new();
-        buf.extend(self.header.as_buf());
-        self.questions.iter().for_each(|question| {
-            buf.extend(question.as_buf());
-        });
-        self.answers.iter().for_each(|answer| {
-            buf.extend(answer.as_buf());
-        });
         buf
     }
 
     pub fn merge(dns_messages: Vec<DnsMessage>) -> DnsMessage {
         let mut dns_header = dns_messages[0].header.clone();
-        dns_header.question_count = dns_messages.len() as u16;
-        dns_header.answer_count = dns_messages.len() as u16;
         let mut dns_questions: Vec<DnsQuestion> = vec![];
         let mut dns_answers: Vec<DnsAnswer> = vec![];
+        let mut dns_authorities: Vec<DnsAuthority> = vec![];
+        let mut dns_additional: Vec<DnsAdditional> = vec![];
+        let mut dns_edns: Option<Edns> = None;
         dns_messages.into_iter().for_each(|dns_message| {
+            dns_edns = dns_edns.take().or(dns_message.edns);
             dns_questions.extend(dns_message.questions);
             dns_answers.extend(dns_message.answers);
+            dns_authorities.extend(dns_message.authorities);
+            dns_additional.extend(dns_message.additional);
         });
-        return DnsMessage {
+        dns_header.question_count = dns_questions.len() as u16;
+        dns_header.answer_count = dns_answers.len() as u16;
+        dns_header.authority_count = dns_authorities.len() as u16;
+        dns_header.additional_count = (dns_additional.len() + dns_edns.iter().len()) as u16;
+        DnsMessage {
             header: dns_header,
             questions: dns_questions,
             answers: dns_answers,
-            authorities: vec![],
-            additional: vec![],
+            authorities: dns_authorities,
+            additional: dns_additional,
+            edns: dns_edns,
+        }
+    }
+
+    /// Largest UDP response the sender of this query can accept.
+    pub fn max_udp_response_size(&self) -> usize {
+        self.edns
+            .as_ref()
+            .map(Edns::max_payload_size)
+            .unwrap_or(DEFAULT_UDP_PAYLOAD_SIZE as usize)
+    }
+
+    /// Builds an empty reply carrying `rcode` for the given request header. RA is left clear for
+    /// the server to set if it offers recursion.
+    pub fn new_error_response(request_header: &DnsHeader, rcode: DnsHeaderRcode) -> Self {
+        let header = DnsHeader {
+            id: request_header.id,
+            query_response: DnsHeaderQR::Reply,
+            opcode: request_header.opcode,
+            authoritative_answer: DnsHeaderAA::NonAuthoritative,
+            truncation: DnsHeaderTC::NotTruncated,
+            recursion_desired: request_header.recursion_desired,
+            recursion_available: DnsHeaderRA::RecursionNotAvailable,
+            z: DnsHeaderZ::Reserved,
+            authentic_data: DnsHeaderAD::NotAuthenticated,
+            // CD is copied from the query (RFC 4035 section 3.1.6)
+            checking_disabled: request_header.checking_disabled,
+            rcode,
+            question_count: 0,
+            answer_count: 0,
+            authority_count: 0,
+            additional_count: 0,
         };
+
+        Self::new(header, vec![], vec![], vec![], vec![])
+    }
+
+    /// Builds a FORMERR reply for a message that could not be parsed, echoing whatever
+    /// of its header can be recovered. Returns `None` when not even the ID is present, or when
+    /// the message is itself a reply, which is never answered.
+    pub fn new_format_error(data: &[u8]) -> Option<Self> {
+        let id = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+        let flags = data.get(2).copied().unwrap_or(0);
+        let more_flags = data.get(3).copied().unwrap_or(0);
+        if flags & 0x80 != 0 {
+            return None;
+        }
+        let header = DnsHeader {
+            id,
+            query_response: DnsHeaderQR::Reply,
+            opcode: DnsHeaderOpcode::from(flags),
+            authoritative_answer: DnsHeaderAA::NonAuthoritative,
+            truncation: DnsHeaderTC::NotTruncated,
+            recursion_desired: DnsHeaderRD::from(flags),
+            recursion_available: DnsHeaderRA::RecursionNotAvailable,
+            z: DnsHeaderZ::Reserved,
+            authentic_data: DnsHeaderAD::NotAuthenticated,
+            checking_disabled: DnsHeaderCD::from(more_flags),
+            rcode: DnsHeaderRcode::FormatError,
+            question_count: 0,
+            answer_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+        };
+
+        Some(Self::new_error_response(&header, DnsHeaderRcode::FormatError))
     }
 }
 
-impl From<[u8; 512]> for DnsMessage {
-    fn from(data: [u8; 512]) -> Self {
-        let header = DnsHeader::from(&data[0..12]);
+impl DnsMessage {
+    /// Parses a message from the start of `data` and returns it along with the number of bytes it
+    /// occupied, leaving anything after the final record untouched.
+    pub fn from_buf(data: &[u8]) -> Result<(Self, usize), DnsError> {
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(DnsError::MessageTooLarge(data.len()));
+        }
+
+        let header = DnsHeader::try_from(data)?;
         let mut next_section_skip: usize = 12;
-        
+
         let mut questions = vec![];
         let mut answers = vec![];
-        let authorities = vec![];
-        let additional = vec![];
+        let mut authorities = vec![];
+        let mut additional = vec![];
 
         for _ in 0..header.question_count {
-            let question = DnsQuestion::from_buf(&data, next_section_skip);
+            let question = DnsQuestion::from_buf(data, next_section_skip)?;
             next_section_skip += question.length;
             questions.push(question);
         }
 
         for _ in 0..header.answer_count {
-            let answer = DnsAnswer::from(&data[next_section_skip..]);
+            let (answer, length) = DnsAnswer::from_buf(data, next_section_skip)?;
+            next_section_skip += length;
             answers.push(answer);
         }
 
-        Self {
+        for _ in 0..header.authority_count {
+            let (authority, length) = DnsAuthority::from_buf(data, next_section_skip)?;
+            next_section_skip += length;
+            authorities.push(authority);
+        }
+
+        let mut edns = None;
+        for _ in 0..header.additional_count {
+            let (record, length) = DnsAdditional::from_buf(data, next_section_skip)?;
+            next_section_skip += length;
+
+            match record.qtype {
+                // at most one OPT record is allowed (RFC 6891 section 6.1.1)
+                DnsType::OPT if edns.is_some() => return Err(DnsError::InvalidOpt),
+                DnsType::OPT => edns = Some(Edns::from_record(&record)?),
+                _ => additional.push(record),
+            }
+        }
+
+        let message = Self {
             header,
             questions,
             answers,
             authorities,
             additional,
+            edns,
+        };
+
+        Ok((message, next_section_skip))
+    }
+}
+
+impl TryFrom<&[u8]> for DnsMessage {
+    type Error = DnsError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let (message, length) = Self::from_buf(data)?;
+        if length != data.len() {
+            return Err(DnsError::TrailingData { offset: length });
         }
+
+        Ok(message)
+    }
+}
+
+impl TryFrom<&Bytes> for DnsMessage {
+    type Error = DnsError;
+
+    fn try_from(data: &Bytes) -> Result<Self, Self::Error> {
+        Self::try_from(data.as_ref())
+    }
+}
+
+impl TryFrom<Bytes> for DnsMessage {
+    type Error = DnsError;
+
+    fn try_from(data: Bytes) -> Result<Self, Self::Error> {
+        Self::try_from(&data)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::dns::header::*;
+    use std::net::Ipv4Addr;
+
+    use bytes::Bytes;
+
+    use crate::dns::{
+        answer::DnsAnswer,
+        common::{DnsClass, DnsName, DnsType},
+        header::*,
+        message::DnsMessage,
+        question::DnsQuestion,
+        rdata::{DnsSoa, RData},
+    };
+    use crate::error::DnsError;
 
     #[test]
     fn test_dns_message_from_bytes() {
@@ -169,6 +315,8 @@ mod tests {
             recursion_desired: DnsHeaderRD::RecursionNotDesired,
             recursion_available: DnsHeaderRA::RecursionAvailable,
             z: DnsHeaderZ::Reserved,
+            authentic_data: DnsHeaderAD::NotAuthenticated,
+            checking_disabled: DnsHeaderCD::CheckingEnabled,
             rcode: DnsHeaderRcode::Refused,
             question_count: 1,
             answer_count: 1,
@@ -177,8 +325,157 @@ mod tests {
         };
 
         let header_buf = header.as_buf();
-        let header_parsed = DnsHeader::from(&header_buf[0..12]);
+        let header_parsed = DnsHeader::try_from(&header_buf[0..12]).unwrap();
 
         debug_assert_eq!(header, header_parsed);
+
+        // the bits after RA and RCODEs we have no name for come back as they went out
+        let header = DnsHeader {
+            z: DnsHeaderZ::Set,
+            authentic_data: DnsHeaderAD::Authenticated,
+            checking_disabled: DnsHeaderCD::CheckingDisabled,
+            rcode: DnsHeaderRcode::Unknown(11),
+            ..header
+        };
+        let header_buf = header.as_buf();
+        assert_eq!(header_buf[3], 0b1111_1011);
+        assert_eq!(DnsHeader::try_from(&header_buf[0..12]).unwrap(), header);
+    }
+
+    #[test]
+    fn test_dns_message_rejects_malformed_bytes() {
+        // header needs 12 bytes
+        let err = DnsHeader::try_from(&[0u8; 4][..]).unwrap_err();
+        assert_eq!(err, DnsError::Truncated { offset: 0, needed: 12 });
+
+        // opcode 15 is unassigned, but still parses so it can be answered NOTIMP in kind
+        let mut data = [0u8; 12];
+        data[2] = 0b01111000;
+        let message = DnsMessage::try_from(&data[..]).unwrap();
+        assert_eq!(message.header.opcode, DnsHeaderOpcode::Unknown(15));
+        let response =
+            DnsMessage::new_error_response(&message.header, DnsHeaderRcode::NotImplemented);
+        assert_eq!(response.header.as_buf()[2], 0b11111000);
+    }
+
+    #[test]
+    fn test_dns_message_variable_length() {
+        let mut header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        header.question_count = 40;
+        let questions = (0..40)
+            .map(|i| DnsQuestion::new(&format!("host{i}.example.com"), DnsType::A, DnsClass::IN))
+            .collect();
+        let message = DnsMessage::new(header, questions, vec![], vec![], vec![]);
+        let buf = message.as_buf().freeze();
+        assert!(buf.len() > 512);
+
+        let parsed = DnsMessage::try_from(&buf).unwrap();
+        assert_eq!(parsed.questions.len(), 40);
+        assert_eq!(parsed.questions[39].name.name, "host39.example.com");
+
+        // anything past the last record is rejected
+        let mut padded = buf.to_vec();
+        padded.extend([0, 0]);
+        let err = DnsMessage::try_from(Bytes::from(padded)).unwrap_err();
+        assert_eq!(err, DnsError::TrailingData { offset: buf.len() });
+    }
+
+    #[test]
+    fn test_dns_message_compresses_names() {
+        let mut header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        header.question_count = 1;
+        header.answer_count = 2;
+        let questions = vec![DnsQuestion::new("www.example.com", DnsType::CNAME, DnsClass::IN)];
+        let answers = vec![
+            DnsAnswer::new(
+                "www.example.com",
+                DnsType::CNAME,
+                DnsClass::IN,
+                60,
+                RData::CNAME(DnsName::new("web.example.com".to_string())),
+            ),
+            DnsAnswer::new(
+                "web.example.com",
+                DnsType::A,
+                DnsClass::IN,
+                60,
+                RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+            ),
+        ];
+        let message = DnsMessage::new(header, questions, answers, vec![], vec![]);
+
+        let compressed = message.as_buf();
+        let uncompressed = message.encode(false);
+        assert!(compressed.len() < uncompressed.len());
+        // the answer owner points straight back at the question name
+        assert_eq!(&compressed[33..35], &[0xC0, 0x0C]);
+
+        for buf in [compressed, uncompressed] {
+            let parsed = DnsMessage::try_from(&buf[..]).unwrap();
+            assert_eq!(parsed.answers[0].name.name, "www.example.com");
+            assert_eq!(parsed.answers[0].data, message.answers[0].data);
+            assert_eq!(parsed.answers[1].name.name, "web.example.com");
+        }
+    }
+
+    #[test]
+    fn test_dns_message_round_trips_all_sections() {
+        let header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        let soa = RData::SOA(DnsSoa {
+            mname: DnsName::new("ns1.example.com".to_string()),
+            rname: DnsName::new("admin.example.com".to_string()),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 604800,
+            minimum: 300,
+        });
+        let ns = RData::NS(DnsName::new("ns1.example.com".to_string()));
+        let glue = RData::A(Ipv4Addr::new(192, 0, 2, 1));
+        let message = DnsMessage::new(
+            header,
+            vec![DnsQuestion::new("missing.example.com", DnsType::A, DnsClass::IN)],
+            vec![],
+            vec![
+                DnsAnswer::new("example.com", DnsType::SOA, DnsClass::IN, 300, soa.clone()),
+                DnsAnswer::new("example.com", DnsType::NS, DnsClass::IN, 300, ns),
+            ],
+            vec![DnsAnswer::new("ns1.example.com", DnsType::A, DnsClass::IN, 300, glue.clone())],
+        );
+
+        let buf = message.as_buf();
+        assert_eq!(&buf[6..12], &[0, 0, 0, 2, 0, 1]);
+
+        let parsed = DnsMessage::try_from(&buf[..]).unwrap();
+        assert_eq!(parsed.header.authority_count, 2);
+        assert_eq!(parsed.authorities[0].data, soa);
+        assert_eq!(parsed.authorities[1].name.name, "example.com");
+        assert_eq!(parsed.additional[0].name.name, "ns1.example.com");
+        assert_eq!(parsed.additional[0].data, glue);
+    }
+
+    #[test]
+    fn test_dns_message_truncates_whole_rrsets() {
+        let header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        let a = |name: &str, last_octet: u8| {
+            let rdata = RData::A(Ipv4Addr::new(192, 0, 2, last_octet));
+            DnsAnswer::new(name, DnsType::A, DnsClass::IN, 60, rdata)
+        };
+        let answers: Vec<DnsAnswer> = (0..20).map(|i| a("www.example.com", i)).collect();
+        let glue: Vec<DnsAnswer> = (0..20).map(|i| a("ns1.example.com", i)).collect();
+        let mut message = DnsMessage::new(header, vec![], answers, vec![], glue);
+
+        // dropping the glue RRset is enough, no TC needed
+        let buf = message.truncate_to(512);
+        assert!(buf.len() <= 512);
+        assert!(message.additional.is_empty());
+        assert_eq!(message.answers.len(), 20);
+        assert_eq!(message.header.truncation, DnsHeaderTC::NotTruncated);
+
+        // the answer RRset goes as a whole and TC is set
+        let buf = message.truncate_to(100);
+        assert_eq!(buf.len(), 12);
+        assert!(message.answers.is_empty());
+        assert_eq!(message.header.truncation, DnsHeaderTC::Truncated);
     }
 }
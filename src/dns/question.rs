@@ -1,9 +1,11 @@
-use std::{ops::BitAnd, str};
-
-use bit::BitIndex;
 use bytes::{BufMut, BytesMut};
 
-use super::common::{DnsClass, DnsName, DnsType};
+use crate::error::DnsError;
+
+use super::{
+    common::{read_u16, DnsClass, DnsName, DnsType},
+    encoder::DnsEncoder,
+};
 
 #[derive(Debug, Clone)]
 pub struct DnsQuestion {
@@ -27,35 +29,27 @@ impl DnsQuestion {
         let mut buf = BytesMut::new();
         // process name parts
         buf.put(self.name.as_buf());
-        buf.put_u16(self.qtype.clone() as u16);
-        buf.put_u16(self.qclass.clone() as u16);
+        buf.put_u16(u16::from(self.qtype));
+        buf.put_u16(u16::from(self.qclass));
         buf
     }
 
-    pub fn from_buf(data: &[u8; 512], start_index: usize) -> Self {
-        let name = DnsName::read(&data[start_index..]);
-        let name_length = name.length;
-        let mut skip = start_index + name_length;
-
-        // there's a name pointer, read it and resolve
-        if let Some(offset) = name.offset {
-            let name_completion = DnsName::read(&data[offset..]);
-            skip += 2;
-
-            return Self {
-                name: DnsName::new(format!("{}.{}", name.name, name_completion.name)),
-                qtype: DnsType::from(u16::from_be_bytes([data[skip], data[skip + 1]])),
-                qclass: DnsClass::from(u16::from_be_bytes([data[skip + 2], data[skip + 3]])),
-                length: name_length + 6,
-            }
-        }
+    pub fn write(&self, encoder: &mut DnsEncoder) {
+        encoder.put_name(&self.name);
+        encoder.buf().put_u16(u16::from(self.qtype));
+        encoder.buf().put_u16(u16::from(self.qclass));
+    }
 
-        // no name pointer, just resolve
-        Self {
+    pub fn from_buf(data: &[u8], start_index: usize) -> Result<Self, DnsError> {
+        let name = DnsName::from_buf(data, start_index)?;
+        let skip = start_index + name.length;
+        let length = name.length + 4;
+
+        Ok(Self {
             name,
-            qtype: DnsType::from(u16::from_be_bytes([data[skip], data[skip + 1]])),
-            qclass: DnsClass::from(u16::from_be_bytes([data[skip + 2], data[skip + 3]])),
-            length: name_length + 4,
-        }
+            qtype: DnsType::from(read_u16(data, skip)?),
+            qclass: DnsClass::from(read_u16(data, skip + 2)?),
+            length,
+        })
     }
 }
@@ -0,0 +1,704 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use bytes::{BufMut, BytesMut};
+
+use crate::error::DnsError;
+
+use super::{
+    common::{parse_ttl, read_bytes, read_u16, read_u32, read_u8, DnsName, DnsType},
+    encoder::DnsEncoder,
+};
+
+/// SOA RDATA, kept as its own struct since zone handling and negative caching read its fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsSoa {
+    pub mname: DnsName,
+    pub rname: DnsName,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+/// Typed RDATA for the record types we understand. Anything else is kept as opaque bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(DnsName),
+    CNAME(DnsName),
+    PTR(DnsName),
+    DNAME(DnsName),
+    MX {
+        preference: u16,
+        exchange: DnsName,
+    },
+    TXT(Vec<Vec<u8>>),
+    SOA(DnsSoa),
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DnsName,
+    },
+    CAA {
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+    },
+    HINFO {
+        cpu: Vec<u8>,
+        os: Vec<u8>,
+    },
+    NAPTR {
+        order: u16,
+        preference: u16,
+        flags: Vec<u8>,
+        services: Vec<u8>,
+        regexp: Vec<u8>,
+        replacement: DnsName,
+    },
+    SSHFP {
+        algorithm: u8,
+        fingerprint_type: u8,
+        fingerprint: Vec<u8>,
+    },
+    Unknown(Vec<u8>),
+}
+
+impl RData {
+    /// Parses `length` bytes of RDATA for `qtype` starting at `start_index` in a full message
+    /// buffer, so compressed names can be resolved.
+    pub fn from_buf(
+        qtype: DnsType,
+        data: &[u8],
+        start_index: usize,
+        length: usize,
+    ) -> Result<Self, DnsError> {
+        let rdata = read_bytes(data, start_index, length)?;
+        let end = start_index + length;
+        let mut skip = start_index;
+
+        let read_name = |skip: &mut usize| -> Result<DnsName, DnsError> {
+            let name = DnsName::from_buf(data, *skip)?;
+            *skip += name.length;
+            Ok(name)
+        };
+        let read_string = |skip: &mut usize| -> Result<Vec<u8>, DnsError> {
+            let string_length = read_u8(data, *skip)? as usize;
+            let string = read_bytes(data, *skip + 1, string_length)?.to_vec();
+            *skip += string_length + 1;
+            Ok(string)
+        };
+
+        let rdata = match qtype {
+            DnsType::A => {
+                let octets: [u8; 4] = rdata.try_into().map_err(|_| DnsError::InvalidRdata {
+                    offset: start_index,
+                })?;
+                skip = end;
+                RData::A(Ipv4Addr::from(octets))
+            }
+            DnsType::AAAA => {
+                let octets: [u8; 16] = rdata.try_into().map_err(|_| DnsError::InvalidRdata {
+                    offset: start_index,
+                })?;
+                skip = end;
+                RData::AAAA(Ipv6Addr::from(octets))
+            }
+            DnsType::NS => RData::NS(read_name(&mut skip)?),
+            DnsType::CNAME => RData::CNAME(read_name(&mut skip)?),
+            DnsType::PTR => RData::PTR(read_name(&mut skip)?),
+            DnsType::DNAME => RData::DNAME(read_name(&mut skip)?),
+            DnsType::MX => {
+                skip += 2;
+                RData::MX {
+                    preference: read_u16(data, start_index)?,
+                    exchange: read_name(&mut skip)?,
+                }
+            }
+            DnsType::TXT => {
+                let mut strings = vec![];
+                while skip < end {
+                    strings.push(read_string(&mut skip)?);
+                }
+                RData::TXT(strings)
+            }
+            DnsType::SOA => {
+                let mname = read_name(&mut skip)?;
+                let rname = read_name(&mut skip)?;
+                let soa = DnsSoa {
+                    mname,
+                    rname,
+                    serial: read_u32(data, skip)?,
+                    refresh: read_u32(data, skip + 4)?,
+                    retry: read_u32(data, skip + 8)?,
+                    expire: read_u32(data, skip + 12)?,
+                    minimum: read_u32(data, skip + 16)?,
+                };
+                skip += 20;
+                RData::SOA(soa)
+            }
+            DnsType::SRV => {
+                skip += 6;
+                RData::SRV {
+                    priority: read_u16(data, start_index)?,
+                    weight: read_u16(data, start_index + 2)?,
+                    port: read_u16(data, start_index + 4)?,
+                    target: read_name(&mut skip)?,
+                }
+            }
+            DnsType::CAA => {
+                let flags = read_u8(data, skip)?;
+                skip += 1;
+                let tag = String::from_utf8(read_string(&mut skip)?).map_err(|_| {
+                    DnsError::InvalidRdata {
+                        offset: start_index,
+                    }
+                })?;
+                // the tag must leave room for the value, rather than run into the next record
+                if skip > end {
+                    return Err(DnsError::InvalidRdata {
+                        offset: start_index,
+                    });
+                }
+                let value = read_bytes(data, skip, end - skip)?.to_vec();
+                skip += value.len();
+                RData::CAA { flags, tag, value }
+            }
+            DnsType::HINFO => RData::HINFO {
+                cpu: read_string(&mut skip)?,
+                os: read_string(&mut skip)?,
+            },
+            DnsType::NAPTR => {
+                skip += 4;
+                RData::NAPTR {
+                    order: read_u16(data, start_index)?,
+                    preference: read_u16(data, start_index + 2)?,
+                    flags: read_string(&mut skip)?,
+                    services: read_string(&mut skip)?,
+                    regexp: read_string(&mut skip)?,
+                    replacement: read_name(&mut skip)?,
+                }
+            }
+            DnsType::SSHFP => {
+                if length < 2 {
+                    return Err(DnsError::InvalidRdata {
+                        offset: start_index,
+                    });
+                }
+                skip = end;
+                RData::SSHFP {
+                    algorithm: rdata[0],
+                    fingerprint_type: rdata[1],
+                    fingerprint: rdata[2..].to_vec(),
+                }
+            }
+            // opaque pass-through for everything we don't model (RFC 3597), OPT options are
+            // decoded by `Edns` and meta-types only carry RDATA in updates, where it's empty
+            DnsType::WKS
+            | DnsType::OPT
+            | DnsType::IXFR
+            | DnsType::AXFR
+            | DnsType::ANY
+            | DnsType::Unknown(_) => {
+                skip = end;
+                RData::Unknown(rdata.to_vec())
+            }
+        };
+
+        // whatever was parsed has to account for exactly RDLENGTH bytes
+        if skip != end {
+            return Err(DnsError::InvalidRdata {
+                offset: start_index,
+            });
+        }
+
+        Ok(rdata)
+    }
+
+    /// Writes the RDATA through a message encoder. Only the names RFC 1035 defines as
+    /// compressible (NS, CNAME, PTR, MX, SOA) are compressed, per RFC 3597 section 4.
+    pub fn write(&self, encoder: &mut DnsEncoder) {
+        match self {
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => encoder.put_name(name),
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                encoder.buf().put_u16(*preference);
+                encoder.put_name(exchange);
+            }
+            RData::SOA(soa) => {
+                encoder.put_name(&soa.mname);
+                encoder.put_name(&soa.rname);
+                encoder.buf().put_u32(soa.serial);
+                encoder.buf().put_u32(soa.refresh);
+                encoder.buf().put_u32(soa.retry);
+                encoder.buf().put_u32(soa.expire);
+                encoder.buf().put_u32(soa.minimum);
+            }
+            _ => encoder.buf().extend(self.as_buf()),
+        }
+    }
+
+    /// Serializes the RDATA with every name written in full.
+    pub fn as_buf(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        let put_string = |buf: &mut BytesMut, string: &[u8]| {
+            buf.put_u8(string.len() as u8);
+            buf.put_slice(string);
+        };
+
+        match self {
+            RData::A(address) => buf.put_slice(&address.octets()),
+            RData::AAAA(address) => buf.put_slice(&address.octets()),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) | RData::DNAME(name) => {
+                buf.put(name.as_buf())
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                buf.put_u16(*preference);
+                buf.put(exchange.as_buf());
+            }
+            RData::TXT(strings) => strings
+                .iter()
+                .for_each(|string| put_string(&mut buf, string)),
+            RData::SOA(soa) => {
+                buf.put(soa.mname.as_buf());
+                buf.put(soa.rname.as_buf());
+                buf.put_u32(soa.serial);
+                buf.put_u32(soa.refresh);
+                buf.put_u32(soa.retry);
+                buf.put_u32(soa.expire);
+                buf.put_u32(soa.minimum);
+            }
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                buf.put_u16(*priority);
+                buf.put_u16(*weight);
+                buf.put_u16(*port);
+                buf.put(target.as_buf());
+            }
+            RData::CAA { flags, tag, value } => {
+                buf.put_u8(*flags);
+                put_string(&mut buf, tag.as_bytes());
+                buf.put_slice(value);
+            }
+            RData::HINFO { cpu, os } => {
+                put_string(&mut buf, cpu);
+                put_string(&mut buf, os);
+            }
+            RData::NAPTR {
+                order,
+                preference,
+                flags,
+                services,
+                regexp,
+                replacement,
+            } => {
+                buf.put_u16(*order);
+                buf.put_u16(*preference);
+                put_string(&mut buf, flags);
+                put_string(&mut buf, services);
+                put_string(&mut buf, regexp);
+                buf.put(replacement.as_buf());
+            }
+            RData::SSHFP {
+                algorithm,
+                fingerprint_type,
+                fingerprint,
+            } => {
+                buf.put_u8(*algorithm);
+                buf.put_u8(*fingerprint_type);
+                buf.put_slice(fingerprint);
+            }
+            RData::Unknown(data) => buf.put_slice(data),
+        }
+
+        buf
+    }
+}
+
+impl RData {
+    /// Parses the RFC 3597 generic presentation form (`\# 4 C0000201`) for any type. Known types
+    /// are decoded into their typed variant.
+    pub fn from_generic(qtype: DnsType, text: &str) -> Result<Self, DnsError> {
+        let invalid = || DnsError::InvalidText(text.to_string());
+        let mut tokens = text.split_whitespace();
+        if tokens.next() != Some("\\#") {
+            return Err(invalid());
+        }
+        let length: usize = tokens
+            .next()
+            .and_then(|length| length.parse().ok())
+            .ok_or_else(invalid)?;
+
+        let hex: String = tokens.collect();
+        if hex.len() != length * 2 {
+            return Err(invalid());
+        }
+        let data = parse_hex(&hex).ok_or_else(invalid)?;
+
+        Self::from_buf(qtype, &data, 0, length)
+    }
+
+    /// Parses RDATA from the whitespace separated fields of a master file record, with quotes
+    /// already removed. Relative names are completed with `origin`, and any type accepts the
+    /// RFC 3597 generic form.
+    pub fn from_text(qtype: DnsType, fields: &[&str], origin: &DnsName) -> Result<Self, DnsError> {
+        if fields.first() == Some(&"\\#") {
+            return Self::from_generic(qtype, &fields.join(" "));
+        }
+
+        let invalid = || DnsError::InvalidText(fields.join(" "));
+        let field = |index: usize| fields.get(index).copied().ok_or_else(invalid);
+        let name = |index: usize| DnsName::from_text(field(index)?, origin);
+        let string = |index: usize| parse_string(field(index)?);
+        let expect_fields = |count: usize| match fields.len() == count {
+            true => Ok(()),
+            false => Err(invalid()),
+        };
+
+        let rdata = match qtype {
+            DnsType::A => {
+                expect_fields(1)?;
+                RData::A(parse_field(fields, 0)?)
+            }
+            DnsType::AAAA => {
+                expect_fields(1)?;
+                RData::AAAA(parse_field(fields, 0)?)
+            }
+            DnsType::NS => {
+                expect_fields(1)?;
+                RData::NS(name(0)?)
+            }
+            DnsType::CNAME => {
+                expect_fields(1)?;
+                RData::CNAME(name(0)?)
+            }
+            DnsType::PTR => {
+                expect_fields(1)?;
+                RData::PTR(name(0)?)
+            }
+            DnsType::DNAME => {
+                expect_fields(1)?;
+                RData::DNAME(name(0)?)
+            }
+            DnsType::MX => {
+                expect_fields(2)?;
+                RData::MX {
+                    preference: parse_field(fields, 0)?,
+                    exchange: name(1)?,
+                }
+            }
+            DnsType::TXT if !fields.is_empty() => RData::TXT(
+                fields
+                    .iter()
+                    .map(|field| parse_string(field))
+                    .collect::<Result<_, _>>()?,
+            ),
+            DnsType::SOA => {
+                expect_fields(7)?;
+                RData::SOA(DnsSoa {
+                    mname: name(0)?,
+                    rname: name(1)?,
+                    serial: parse_field(fields, 2)?,
+                    refresh: parse_ttl(field(3)?)?,
+                    retry: parse_ttl(field(4)?)?,
+                    expire: parse_ttl(field(5)?)?,
+                    minimum: parse_ttl(field(6)?)?,
+                })
+            }
+            DnsType::SRV => {
+                expect_fields(4)?;
+                RData::SRV {
+                    priority: parse_field(fields, 0)?,
+                    weight: parse_field(fields, 1)?,
+                    port: parse_field(fields, 2)?,
+                    target: name(3)?,
+                }
+            }
+            DnsType::CAA => {
+                expect_fields(3)?;
+                RData::CAA {
+                    flags: parse_field(fields, 0)?,
+                    tag: field(1)?.to_string(),
+                    value: string(2)?,
+                }
+            }
+            DnsType::HINFO => {
+                expect_fields(2)?;
+                RData::HINFO {
+                    cpu: string(0)?,
+                    os: string(1)?,
+                }
+            }
+            DnsType::NAPTR => {
+                expect_fields(6)?;
+                RData::NAPTR {
+                    order: parse_field(fields, 0)?,
+                    preference: parse_field(fields, 1)?,
+                    flags: string(2)?,
+                    services: string(3)?,
+                    regexp: string(4)?,
+                    replacement: name(5)?,
+                }
+            }
+            DnsType::SSHFP if fields.len() >= 3 => RData::SSHFP {
+                algorithm: parse_field(fields, 0)?,
+                fingerprint_type: parse_field(fields, 1)?,
+                fingerprint: parse_hex(&fields[2..].concat()).ok_or_else(invalid)?,
+            },
+            _ => return Err(invalid()),
+        };
+
+        Ok(rdata)
+    }
+}
+
+fn parse_field<T: FromStr>(fields: &[&str], index: usize) -> Result<T, DnsError> {
+    fields
+        .get(index)
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| DnsError::InvalidText(fields.join(" ")))
+}
+
+/// Decodes a character-string, resolving `\X` and `\DDD` escapes. Limited to 255 bytes.
+fn parse_string(text: &str) -> Result<Vec<u8>, DnsError> {
+    let invalid = || DnsError::InvalidText(text.to_string());
+    let mut bytes = text.bytes();
+    let mut string = vec![];
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            string.push(byte);
+            continue;
+        }
+        match bytes.next().ok_or_else(invalid)? {
+            digit @ b'0'..=b'9' => {
+                let digits = [
+                    digit,
+                    bytes.next().ok_or_else(invalid)?,
+                    bytes.next().ok_or_else(invalid)?,
+                ];
+                let value = std::str::from_utf8(&digits)
+                    .ok()
+                    .and_then(|digits| digits.parse::<u8>().ok())
+                    .ok_or_else(invalid)?;
+                string.push(value);
+            }
+            escaped => string.push(escaped),
+        }
+    }
+
+    match string.len() <= 255 {
+        true => Ok(string),
+        false => Err(invalid()),
+    }
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len() / 2)
+        .map(|i| u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok())
+        .collect()
+}
+
+/// Writes a character-string in quotes, escaping anything that isn't printable ASCII.
+fn fmt_string(f: &mut fmt::Formatter<'_>, string: &[u8]) -> fmt::Result {
+    write!(f, "\"")?;
+    for &byte in string {
+        match byte {
+            b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+            0x20..=0x7E => write!(f, "{}", byte as char)?,
+            _ => write!(f, "\\{:03}", byte)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+fn fmt_hex(f: &mut fmt::Formatter<'_>, data: &[u8]) -> fmt::Result {
+    data.iter().try_for_each(|byte| write!(f, "{:02X}", byte))
+}
+
+/// Master file presentation of the RDATA. Opaque RDATA uses the RFC 3597 `\# len hex` form.
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(address) => write!(f, "{}", address),
+            RData::AAAA(address) => write!(f, "{}", address),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) | RData::DNAME(name) => {
+                write!(f, "{}", name)
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, exchange),
+            RData::TXT(strings) => {
+                for (i, string) in strings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    fmt_string(f, string)?;
+                }
+                Ok(())
+            }
+            RData::SOA(soa) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                soa.mname, soa.rname, soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum
+            ),
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                write!(f, "{} {} {} {}", priority, weight, port, target)
+            }
+            RData::CAA { flags, tag, value } => {
+                write!(f, "{} {} ", flags, tag)?;
+                fmt_string(f, value)
+            }
+            RData::HINFO { cpu, os } => {
+                fmt_string(f, cpu)?;
+                write!(f, " ")?;
+                fmt_string(f, os)
+            }
+            RData::NAPTR {
+                order,
+                preference,
+                flags,
+                services,
+                regexp,
+                replacement,
+            } => {
+                write!(f, "{} {} ", order, preference)?;
+                fmt_string(f, flags)?;
+                write!(f, " ")?;
+                fmt_string(f, services)?;
+                write!(f, " ")?;
+                fmt_string(f, regexp)?;
+                write!(f, " {}", replacement)
+            }
+            RData::SSHFP {
+                algorithm,
+                fingerprint_type,
+                fingerprint,
+            } => {
+                write!(f, "{} {} ", algorithm, fingerprint_type)?;
+                fmt_hex(f, fingerprint)
+            }
+            RData::Unknown(data) if data.is_empty() => write!(f, "\\# 0"),
+            RData::Unknown(data) => {
+                write!(f, "\\# {} ", data.len())?;
+                fmt_hex(f, data)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::RData;
+    use crate::dns::common::{DnsName, DnsType};
+    use crate::error::DnsError;
+
+    #[test]
+    fn test_rdata_round_trips_typed_records() {
+        let records = [
+            (
+                DnsType::AAAA,
+                RData::AAAA(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            ),
+            (
+                DnsType::TXT,
+                RData::TXT(vec![b"v=spf1 -all".to_vec(), vec![]]),
+            ),
+            (
+                DnsType::SRV,
+                RData::SRV {
+                    priority: 10,
+                    weight: 5,
+                    port: 5060,
+                    target: DnsName::new("sip.example.com".to_string()),
+                },
+            ),
+            (
+                DnsType::CAA,
+                RData::CAA {
+                    flags: 0,
+                    tag: "issue".to_string(),
+                    value: b"letsencrypt.org".to_vec(),
+                },
+            ),
+            (
+                DnsType::NAPTR,
+                RData::NAPTR {
+                    order: 100,
+                    preference: 10,
+                    flags: b"S".to_vec(),
+                    services: b"SIP+D2U".to_vec(),
+                    regexp: vec![],
+                    replacement: DnsName::new("_sip._udp.example.com".to_string()),
+                },
+            ),
+            (
+                DnsType::SSHFP,
+                RData::SSHFP {
+                    algorithm: 4,
+                    fingerprint_type: 2,
+                    fingerprint: vec![0xAB; 32],
+                },
+            ),
+        ];
+
+        for (qtype, rdata) in records {
+            let buf = rdata.as_buf();
+            assert_eq!(RData::from_buf(qtype, &buf, 0, buf.len()).unwrap(), rdata);
+        }
+
+        // an A record is always four bytes, whatever RDLENGTH says
+        let err = RData::from_buf(DnsType::A, &[1, 2, 3, 4, 5], 0, 5).unwrap_err();
+        assert_eq!(err, DnsError::InvalidRdata { offset: 0 });
+
+        // a CAA tag running past RDLENGTH into whatever follows
+        let err = RData::from_buf(DnsType::CAA, b"\x00\x05issue;", 0, 4).unwrap_err();
+        assert_eq!(err, DnsError::InvalidRdata { offset: 0 });
+    }
+
+    #[test]
+    fn test_rdata_generic_presentation() {
+        let qtype: DnsType = "TYPE65534".parse().unwrap();
+        assert_eq!(qtype, DnsType::Unknown(65534));
+        assert_eq!(qtype.to_string(), "TYPE65534");
+        assert_eq!("type1".parse::<DnsType>().unwrap(), DnsType::A);
+
+        let rdata = RData::from_generic(qtype, "\\# 3 ab cdEF").unwrap();
+        assert_eq!(rdata, RData::Unknown(vec![0xAB, 0xCD, 0xEF]));
+        assert_eq!(rdata.to_string(), "\\# 3 ABCDEF");
+        assert_eq!(RData::Unknown(vec![]).to_string(), "\\# 0");
+
+        // known types given in generic form decode to their typed variant
+        let rdata = RData::from_generic(DnsType::A, "\\# 4 C0000201").unwrap();
+        assert_eq!(rdata, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+
+        assert!(RData::from_generic(qtype, "\\# 2 abcdef").is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum DnsError {
     #[error("Invalid query")]
     InvalidQuery,
@@ -8,4 +8,24 @@ pub enum DnsError {
     InvalidResponse,
     #[error("Invalid message")]
     InvalidMessage,
+    #[error("Buffer truncated: needed {needed} bytes at offset {offset}")]
+    Truncated { offset: usize, needed: usize },
+    #[error("Unexpected data after end of message at offset {offset}")]
+    TrailingData { offset: usize },
+    #[error("Message of {0} bytes exceeds the 65535 byte limit")]
+    MessageTooLarge(usize),
+    #[error("Invalid label at offset {offset}")]
+    BadLabel { offset: usize },
+    #[error("Name starting at offset {offset} exceeds 255 octets")]
+    NameTooLong { offset: usize },
+    #[error("Compression pointer at offset {offset} points to invalid offset {target}")]
+    BadPointer { offset: usize, target: usize },
+    #[error("Compression pointer loop at offset {offset}")]
+    PointerLoop { offset: usize },
+    #[error("Invalid RDATA at offset {offset}")]
+    InvalidRdata { offset: usize },
+    #[error("Invalid or duplicate OPT record")]
+    InvalidOpt,
+    #[error("Invalid presentation format: {0}")]
+    InvalidText(String),
 }
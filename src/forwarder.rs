@@ -0,0 +1,488 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::dns::{
+    common::DnsName,
+    edns::{Edns, EDNS_FLAG_DO},
+    header::{
+        DnsHeader, DnsHeaderAD, DnsHeaderCD, DnsHeaderQR, DnsHeaderRD, DnsHeaderRcode, DnsHeaderTC,
+    },
+    message::{DnsMessage, MAX_MESSAGE_SIZE},
+    question::DnsQuestion,
+};
+
+use self::upstream::UpstreamSet;
+
+pub mod upstream;
+
+/// UDP payload size we advertise to upstreams.
+pub const UPSTREAM_UDP_PAYLOAD_SIZE: u16 = 4096;
+
+/// Sends questions for names at or below `suffix` to their own set of upstreams.
+#[derive(Debug)]
+pub struct ForwardRule {
+    pub suffix: DnsName,
+    pub upstreams: UpstreamSet,
+}
+
+/// Forwards queries to sets of upstream resolvers chosen by the longest matching rule. Every
+/// attempt uses a fresh socket (and so a random source port) and a random ID, and only replies
+/// that match the query are accepted (RFC 5452).
+#[derive(Debug)]
+pub struct Forwarder {
+    // used for names no rule matches
+    pub upstreams: UpstreamSet,
+    pub rules: Vec<ForwardRule>,
+    // how long to wait for each attempt
+    pub timeout: Duration,
+    // attempts per question before giving up, spread across the upstreams
+    pub attempts: usize,
+}
+
+impl Forwarder {
+    pub fn new(upstreams: UpstreamSet, timeout: Duration, attempts: usize) -> Self {
+        Self {
+            upstreams,
+            rules: vec![],
+            timeout,
+            attempts,
+        }
+    }
+
+    pub fn add_rule(&mut self, suffix: DnsName, upstreams: UpstreamSet) {
+        self.rules.push(ForwardRule { suffix, upstreams });
+    }
+
+    /// Whether there is any upstream to send questions about `name` to.
+    pub fn covers(&self, name: &DnsName) -> bool {
+        !self.upstreams_for(name).is_empty()
+    }
+
+    /// Upstreams of the rule with the longest suffix matching `name`, or the default ones.
+    pub fn upstreams_for(&self, name: &DnsName) -> &UpstreamSet {
+        self.rules
+            .iter()
+            .filter(|rule| name.is_subdomain_of(&rule.suffix))
+            .max_by_key(|rule| rule.suffix.label_count())
+            .map_or(&self.upstreams, |rule| &rule.upstreams)
+    }
+
+    /// Asks a single question, moving on to the next upstream after a timeout, an unusable reply
+    /// or a SERVFAIL. If every attempt ends in SERVFAIL the last one is passed on as is. The
+    /// client's CD bit goes along, so it can skip the upstream's validation too.
+    pub fn exchange(
+        &self,
+        question: &DnsQuestion,
+        recursion_desired: DnsHeaderRD,
+        checking_disabled: DnsHeaderCD,
+        dnssec_ok: bool,
+    ) -> anyhow::Result<DnsMessage> {
+        let upstreams = self.upstreams_for(&question.name);
+        let candidates = upstreams.candidates();
+        if candidates.is_empty() {
+            return Err(anyhow!("No upstream resolvers configured"));
+        }
+
+        let mut last_error = anyhow!("No attempts made");
+        let mut last_server_failure = None;
+
+        for upstream in candidates.iter().cycle().take(self.attempts.max(1)) {
+            let mut request = new_request(question, recursion_desired, dnssec_ok);
+            // AD asks the upstream to say whether it validated the answer (RFC 6840 section 5.7)
+            request.header.authentic_data = DnsHeaderAD::Authenticated;
+            request.header.checking_disabled = checking_disabled;
+            let started = Instant::now();
+            match query_upstream(*upstream, &request, self.timeout) {
+                Ok(reply) if reply.header.rcode == DnsHeaderRcode::ServerFailure => {
+                    upstreams.report_failure(*upstream);
+                    last_server_failure = Some(reply);
+                }
+                Ok(reply) => {
+                    upstreams.report_success(*upstream, started.elapsed());
+                    return Ok(reply);
+                }
+                Err(e) => {
+                    upstreams.report_failure(*upstream);
+                    last_error = e.context(format!("Upstream {} did not answer", upstream));
+                }
+            }
+        }
+
+        last_server_failure.ok_or(last_error)
+    }
+}
+
+/// Builds a single-question query with a random ID, advertising our EDNS payload size.
+pub fn new_request(
+    question: &DnsQuestion,
+    recursion_desired: DnsHeaderRD,
+    dnssec_ok: bool,
+) -> DnsMessage {
+    let mut header = DnsHeader::try_from(&[0u8; 12][..]).expect("a zeroed header is valid");
+    header.id = rand::random();
+    header.recursion_desired = recursion_desired;
+
+    let mut edns = Edns::new(UPSTREAM_UDP_PAYLOAD_SIZE);
+    if dnssec_ok {
+        edns.flags |= EDNS_FLAG_DO;
+    }
+
+    let mut request = DnsMessage::new(header, vec![question.clone()], vec![], vec![], vec![]);
+    request.edns = Some(edns);
+    request
+}
+
+/// Sends `request` to `upstream` over UDP, retrying over TCP if the reply comes back truncated.
+pub fn query_upstream(
+    upstream: SocketAddr,
+    request: &DnsMessage,
+    timeout: Duration,
+) -> anyhow::Result<DnsMessage> {
+    let reply = query_udp(upstream, request, timeout)?;
+    if reply.header.truncation == DnsHeaderTC::Truncated {
+        return query_tcp(upstream, request, timeout);
+    }
+    Ok(reply)
+}
+
+fn query_udp(
+    upstream: SocketAddr,
+    request: &DnsMessage,
+    timeout: Duration,
+) -> anyhow::Result<DnsMessage> {
+    let bind_addr = match upstream {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind_addr).context("Failed to bind upstream socket")?;
+    socket
+        .send_to(&request.as_buf(), upstream)
+        .context("Failed to send request upstream")?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; MAX_MESSAGE_SIZE];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(anyhow!("Timed out waiting for {}", upstream));
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (size, source) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(anyhow!("Timed out waiting for {}", upstream));
+            }
+            Err(e) => return Err(e).context("Failed to receive response from upstream"),
+        };
+
+        // anything that isn't a well-formed reply to this exact query is dropped, and we keep
+        // waiting for the real one
+        if source != upstream {
+            continue;
+        }
+        match DnsMessage::try_from(&buf[..size]) {
+            Ok(reply) if is_reply_to(request, &reply) => return Ok(reply),
+            _ => continue,
+        }
+    }
+}
+
+fn query_tcp(
+    upstream: SocketAddr,
+    request: &DnsMessage,
+    timeout: Duration,
+) -> anyhow::Result<DnsMessage> {
+    let mut stream = TcpStream::connect_timeout(&upstream, timeout)
+        .with_context(|| format!("Failed to connect to {}", upstream))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let request_buf = request.as_buf();
+    let mut framed = (request_buf.len() as u16).to_be_bytes().to_vec();
+    framed.extend(request_buf);
+    stream.write_all(&framed)?;
+
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length)?;
+    let mut buf = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut buf)?;
+
+    let reply = DnsMessage::try_from(&buf[..]).context("Failed to parse upstream response")?;
+    if !is_reply_to(request, &reply) {
+        return Err(anyhow!("Upstream {} sent a mismatched reply", upstream));
+    }
+    Ok(reply)
+}
+
+/// A reply must echo the ID and question of the request it answers.
+fn is_reply_to(request: &DnsMessage, reply: &DnsMessage) -> bool {
+    reply.header.query_response == DnsHeaderQR::Reply
+        && reply.header.id == request.header.id
+        && reply.questions.len() == request.questions.len()
+        && reply
+            .questions
+            .iter()
+            .zip(request.questions.iter())
+            .all(|(a, b)| a.name == b.name && a.qtype == b.qtype && a.qclass == b.qclass)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, UdpSocket},
+        thread,
+        time::Duration,
+    };
+
+    use super::{
+        upstream::{Strategy, UpstreamSet},
+        Forwarder,
+    };
+    use crate::{
+        cache::Cache,
+        dns::{
+            answer::DnsAnswer,
+            common::{DnsClass, DnsName, DnsType},
+            header::{DnsHeader, DnsHeaderAD, DnsHeaderCD, DnsHeaderQR, DnsHeaderRD},
+            message::DnsMessage,
+            question::DnsQuestion,
+            rdata::RData,
+        },
+        server::Server,
+        zone::catalog::Catalog,
+    };
+
+    #[test]
+    fn test_forwarder_ignores_mismatched_replies() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let (size, source) = upstream.recv_from(&mut buf).unwrap();
+            let mut reply = DnsMessage::try_from(&buf[..size]).unwrap();
+            reply.header.query_response = DnsHeaderQR::Reply;
+
+            // a spoofed reply with the wrong ID comes first
+            let mut spoofed = DnsMessage::try_from(&buf[..size]).unwrap();
+            spoofed.header.query_response = DnsHeaderQR::Reply;
+            spoofed.header.id = reply.header.id.wrapping_add(1);
+            let rdata = RData::A(Ipv4Addr::new(6, 6, 6, 6));
+            spoofed.answers = vec![DnsAnswer::new(
+                "example.com",
+                DnsType::A,
+                DnsClass::IN,
+                60,
+                rdata,
+            )];
+            upstream.send_to(&spoofed.as_buf(), source).unwrap();
+
+            let rdata = RData::A(Ipv4Addr::new(192, 0, 2, 1));
+            reply.answers = vec![DnsAnswer::new(
+                "example.com",
+                DnsType::A,
+                DnsClass::IN,
+                60,
+                rdata,
+            )];
+            upstream.send_to(&reply.as_buf(), source).unwrap();
+        });
+
+        let upstreams = UpstreamSet::new(vec![upstream_addr], Strategy::Failover, Duration::ZERO);
+        let forwarder = Forwarder::new(upstreams, Duration::from_secs(2), 1);
+        let question = DnsQuestion::new("example.com", DnsType::A, DnsClass::IN);
+        let reply = forwarder
+            .exchange(
+                &question,
+                DnsHeaderRD::RecursionDesired,
+                DnsHeaderCD::CheckingEnabled,
+                false,
+            )
+            .unwrap();
+        assert_eq!(reply.answers[0].data, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+
+        // this socket is bound but never read from, so every attempt times out
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstreams = UpstreamSet::new(
+            vec![silent.local_addr().unwrap()],
+            Strategy::Failover,
+            Duration::ZERO,
+        );
+        let forwarder = Forwarder::new(upstreams, Duration::from_millis(50), 2);
+        assert!(forwarder
+            .exchange(
+                &question,
+                DnsHeaderRD::RecursionDesired,
+                DnsHeaderCD::CheckingEnabled,
+                false,
+            )
+            .is_err());
+    }
+
+    /// Upstream that answers every A question with `address`.
+    fn spawn_upstream(address: Ipv4Addr) -> SocketAddr {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok((size, source)) = upstream.recv_from(&mut buf) {
+                let mut reply = DnsMessage::try_from(&buf[..size]).unwrap();
+                reply.header.query_response = DnsHeaderQR::Reply;
+                let name = reply.questions[0].name.name.clone();
+                let rdata = RData::A(address);
+                reply.answers = vec![DnsAnswer::new(&name, DnsType::A, DnsClass::IN, 60, rdata)];
+                upstream.send_to(&reply.as_buf(), source).unwrap();
+            }
+        });
+        upstream_addr
+    }
+
+    #[test]
+    fn test_forwarder_fails_over_to_next_upstream() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let upstream_addr = spawn_upstream(Ipv4Addr::new(192, 0, 2, 1));
+
+        let upstreams = UpstreamSet::new(
+            vec![silent_addr, upstream_addr],
+            Strategy::Failover,
+            Duration::from_secs(30),
+        );
+        let forwarder = Forwarder::new(upstreams, Duration::from_millis(100), 2);
+        let question = DnsQuestion::new("example.com", DnsType::A, DnsClass::IN);
+        assert!(forwarder
+            .exchange(
+                &question,
+                DnsHeaderRD::RecursionDesired,
+                DnsHeaderCD::CheckingEnabled,
+                false,
+            )
+            .is_ok());
+
+        // the silent upstream is sidelined, so the next query goes straight to the working one
+        assert_eq!(
+            forwarder.upstreams.candidates(),
+            vec![upstream_addr, silent_addr]
+        );
+        silent.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 512];
+        while silent.recv_from(&mut buf).is_ok() {}
+        assert!(forwarder
+            .exchange(
+                &question,
+                DnsHeaderRD::RecursionDesired,
+                DnsHeaderCD::CheckingEnabled,
+                false,
+            )
+            .is_ok());
+        assert!(silent.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_forwarder_picks_longest_matching_rule() {
+        let upstream_set = |address| {
+            UpstreamSet::new(
+                vec![spawn_upstream(address)],
+                Strategy::Failover,
+                Duration::ZERO,
+            )
+        };
+        let default = Ipv4Addr::new(192, 0, 2, 1);
+        let corp = Ipv4Addr::new(10, 0, 0, 1);
+        let lab = Ipv4Addr::new(10, 0, 1, 1);
+
+        let mut forwarder = Forwarder::new(upstream_set(default), Duration::from_secs(2), 1);
+        forwarder.add_rule(DnsName::new("corp.internal".into()), upstream_set(corp));
+        forwarder.add_rule(DnsName::new("lab.corp.internal".into()), upstream_set(lab));
+
+        for (name, expected) in [
+            ("example.com", default),
+            ("corp.internal", corp),
+            ("Host.CORP.internal.", corp),
+            ("host.lab.corp.internal", lab),
+            // only whole labels match
+            ("notcorp.internal", default),
+        ] {
+            let question = DnsQuestion::new(name, DnsType::A, DnsClass::IN);
+            let reply = forwarder
+                .exchange(
+                    &question,
+                    DnsHeaderRD::RecursionDesired,
+                    DnsHeaderCD::CheckingEnabled,
+                    false,
+                )
+                .unwrap();
+            assert_eq!(reply.answers[0].data, RData::A(expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_forwarder_passes_dnssec_flags() {
+        // upstream that validated everything, answering differently when asked not to check
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok((size, source)) = upstream.recv_from(&mut buf) {
+                let mut reply = DnsMessage::try_from(&buf[..size]).unwrap();
+                reply.header.query_response = DnsHeaderQR::Reply;
+                reply.header.authentic_data = DnsHeaderAD::Authenticated;
+                let address = match reply.header.checking_disabled {
+                    DnsHeaderCD::CheckingDisabled => Ipv4Addr::new(192, 0, 2, 1),
+                    DnsHeaderCD::CheckingEnabled => Ipv4Addr::new(192, 0, 2, 2),
+                };
+                let name = reply.questions[0].name.name.clone();
+                let rdata = RData::A(address);
+                reply.answers = vec![DnsAnswer::new(&name, DnsType::A, DnsClass::IN, 60, rdata)];
+                upstream.send_to(&reply.as_buf(), source).unwrap();
+            }
+        });
+
+        let upstreams = UpstreamSet::new(vec![upstream_addr], Strategy::Failover, Duration::ZERO);
+        let forwarder = Forwarder::new(upstreams, Duration::from_secs(2), 1);
+        let server = Server::new(Catalog::new(), Some(forwarder), None, Cache::new(16, 4096));
+        let query = |authentic_data, checking_disabled| {
+            let mut header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+            header.authentic_data = authentic_data;
+            header.checking_disabled = checking_disabled;
+            let question = DnsQuestion::new("example.com", DnsType::A, DnsClass::IN);
+            let query = DnsMessage::new(header, vec![question], vec![], vec![], vec![]);
+            server
+                .handle_query(&query, "127.0.0.1".parse().unwrap())
+                .unwrap()
+        };
+
+        // CD goes upstream and comes back, and a client that sets AD learns the answer validated
+        let response = query(DnsHeaderAD::Authenticated, DnsHeaderCD::CheckingDisabled);
+        assert_eq!(
+            response.answers[0].data,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert_eq!(
+            response.header.checking_disabled,
+            DnsHeaderCD::CheckingDisabled
+        );
+        assert_eq!(response.header.authentic_data, DnsHeaderAD::Authenticated);
+
+        // the unchecked answer wasn't cached, and a client that didn't ask doesn't get AD
+        let response = query(DnsHeaderAD::NotAuthenticated, DnsHeaderCD::CheckingEnabled);
+        assert_eq!(
+            response.answers[0].data,
+            RData::A(Ipv4Addr::new(192, 0, 2, 2))
+        );
+        assert_eq!(
+            response.header.authentic_data,
+            DnsHeaderAD::NotAuthenticated
+        );
+    }
+}
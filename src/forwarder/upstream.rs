@@ -0,0 +1,200 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+
+/// How the next upstream is picked for a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    // always the first healthy upstream in configuration order
+    Failover,
+    // rotate through healthy upstreams
+    RoundRobin,
+    // any healthy upstream
+    Random,
+    // the healthy upstream with the lowest smoothed response time
+    LowestLatency,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "failover" => Ok(Strategy::Failover),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "random" => Ok(Strategy::Random),
+            "lowest-latency" => Ok(Strategy::LowestLatency),
+            _ => Err(format!(
+                "unknown strategy {value}, expected failover, round-robin, random or lowest-latency"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::Failover => write!(f, "failover"),
+            Strategy::RoundRobin => write!(f, "round-robin"),
+            Strategy::Random => write!(f, "random"),
+            Strategy::LowestLatency => write!(f, "lowest-latency"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct UpstreamHealth {
+    // smoothed round trip time, unset until the first answer
+    latency: Option<Duration>,
+    // the upstream is skipped until this passes
+    sidelined_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Upstream {
+    addr: SocketAddr,
+    health: Mutex<UpstreamHealth>,
+}
+
+/// A list of upstream resolvers with per-upstream health tracking.
+#[derive(Debug)]
+pub struct UpstreamSet {
+    upstreams: Vec<Upstream>,
+    strategy: Strategy,
+    // upstreams that time out or SERVFAIL are skipped for this long
+    sideline_duration: Duration,
+    next: AtomicUsize,
+}
+
+impl UpstreamSet {
+    pub fn new(addrs: Vec<SocketAddr>, strategy: Strategy, sideline_duration: Duration) -> Self {
+        let upstreams = addrs
+            .into_iter()
+            .map(|addr| Upstream {
+                addr,
+                health: Mutex::new(UpstreamHealth::default()),
+            })
+            .collect();
+
+        Self {
+            upstreams,
+            strategy,
+            sideline_duration,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.upstreams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upstreams.is_empty()
+    }
+
+    /// Upstreams in the order they should be tried for one query. Healthy upstreams come first,
+    /// ordered by the strategy; sidelined ones follow as a last resort, soonest to recover first.
+    pub fn candidates(&self) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let mut healthy = vec![];
+        let mut sidelined = vec![];
+
+        for upstream in &self.upstreams {
+            let health = upstream.health.lock().unwrap();
+            match health.sidelined_until {
+                Some(until) if until > now => sidelined.push((until, upstream.addr)),
+                _ => healthy.push((health.latency.unwrap_or_default(), upstream.addr)),
+            }
+        }
+
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin if !healthy.is_empty() => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                healthy.rotate_left(start);
+            }
+            Strategy::RoundRobin => {}
+            Strategy::Random => healthy.shuffle(&mut rand::thread_rng()),
+            // untried upstreams count as instant so each gets measured at least once
+            Strategy::LowestLatency => healthy.sort_by_key(|(latency, _)| *latency),
+        }
+        sidelined.sort_by_key(|(until, _)| *until);
+
+        healthy
+            .into_iter()
+            .map(|(_, addr)| addr)
+            .chain(sidelined.into_iter().map(|(_, addr)| addr))
+            .collect()
+    }
+
+    pub fn report_success(&self, addr: SocketAddr, latency: Duration) {
+        self.update(addr, |health| {
+            health.sidelined_until = None;
+            // same smoothing as TCP's SRTT, new samples weigh 1/8
+            health.latency = Some(match health.latency {
+                Some(smoothed) => (smoothed * 7 + latency) / 8,
+                None => latency,
+            });
+        });
+    }
+
+    pub fn report_failure(&self, addr: SocketAddr) {
+        let until = Instant::now() + self.sideline_duration;
+        self.update(addr, |health| health.sidelined_until = Some(until));
+    }
+
+    fn update(&self, addr: SocketAddr, update: impl FnOnce(&mut UpstreamHealth)) {
+        if let Some(upstream) = self.upstreams.iter().find(|upstream| upstream.addr == addr) {
+            update(&mut upstream.health.lock().unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use super::{Strategy, UpstreamSet};
+
+    #[test]
+    fn test_upstream_set_orders_candidates() {
+        let addrs: Vec<SocketAddr> = ["127.0.0.1:53", "127.0.0.2:53", "127.0.0.3:53"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+
+        let failover = UpstreamSet::new(addrs.clone(), Strategy::Failover, Duration::from_secs(30));
+        assert_eq!(failover.candidates(), addrs);
+        // a failing upstream moves to the back until its sideline expires
+        failover.report_failure(addrs[0]);
+        assert_eq!(failover.candidates(), vec![addrs[1], addrs[2], addrs[0]]);
+        failover.report_success(addrs[0], Duration::from_millis(5));
+        assert_eq!(failover.candidates(), addrs);
+
+        let round_robin = UpstreamSet::new(addrs.clone(), Strategy::RoundRobin, Duration::ZERO);
+        assert_eq!(round_robin.candidates()[0], addrs[0]);
+        assert_eq!(round_robin.candidates()[0], addrs[1]);
+        assert_eq!(round_robin.candidates()[0], addrs[2]);
+
+        let fastest = UpstreamSet::new(addrs.clone(), Strategy::LowestLatency, Duration::ZERO);
+        fastest.report_success(addrs[0], Duration::from_millis(80));
+        fastest.report_success(addrs[1], Duration::from_millis(10));
+        fastest.report_success(addrs[2], Duration::from_millis(40));
+        assert_eq!(fastest.candidates(), vec![addrs[1], addrs[2], addrs[0]]);
+
+        assert_eq!(
+            "round-robin".parse::<Strategy>().unwrap(),
+            Strategy::RoundRobin
+        );
+        assert!("fastest".parse::<Strategy>().is_err());
+    }
+}
@@ -1,2 +1,7 @@
+pub mod cache;
 pub mod dns;
 pub mod error;
+pub mod forwarder;
+pub mod resolver;
+pub mod server;
+pub mod zone;
@@ -1,76 +1,275 @@
-#[allow(unused_imports)]
-use std::net::UdpSocket;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+    path::PathBuf,
+    process,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
 
 use clap::Parser;
-use codecrafters_dns_server::dns::{answer::DnsAnswer, message::DnsMessage};
+use codecrafters_dns_server::{
+    cache::Cache,
+    dns::common::DnsName,
+    forwarder::{
+        upstream::{Strategy, UpstreamSet},
+        Forwarder,
+    },
+    resolver::Resolver,
+    server::{
+        tcp::{self, TcpConfig},
+        udp, Server,
+    },
+    zone::{catalog::Catalog, notify::notify_secondaries, secondary::Secondary, Zone, ZonePolicy},
+};
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(long, default_value = "")]
-    resolver: String,
+    /// Zone to serve authoritatively, as a master file path optionally prefixed with the origin
+    /// its relative names start from (example.com=zones/example.com.zone); repeat for several
+    #[arg(long = "zone", value_parser = parse_zone_arg)]
+    zones: Vec<(DnsName, PathBuf)>,
+    /// Zone to serve a copy of, transferred from its primaries, as zone=ip:port[,ip:port...];
+    /// repeat for several
+    #[arg(long = "secondary", value_parser = parse_forward_zone)]
+    secondaries: Vec<(DnsName, Vec<SocketAddr>)>,
+    /// Directory to keep copies of secondary zones in, so they are served straight away after a
+    /// restart
+    #[arg(long)]
+    secondary_dir: Option<PathBuf>,
+    /// Seconds to wait on a primary while checking or transferring a secondary zone
+    #[arg(long, default_value_t = 30)]
+    transfer_timeout: u64,
+    /// Clients allowed to transfer a zone, as zone=ip[,ip...]; repeat for several zones
+    #[arg(long = "allow-transfer", value_parser = parse_zone_clients)]
+    allow_transfer: Vec<(DnsName, Vec<IpAddr>)>,
+    /// Clients allowed to change a zone with dynamic updates, as zone=ip[,ip...]; repeat for
+    /// several zones. Each change is written to the zone file, rewriting it in full, before it's
+    /// acknowledged
+    #[arg(long = "allow-update", value_parser = parse_zone_clients)]
+    allow_update: Vec<(DnsName, Vec<IpAddr>)>,
+    /// Secondaries to send a NOTIFY to when a zone changes, as zone=ip:port[,ip:port...]; repeat
+    /// for several zones
+    #[arg(long = "notify", value_parser = parse_forward_zone)]
+    notify: Vec<(DnsName, Vec<SocketAddr>)>,
+    /// Upstream resolvers to forward queries to, as ip:port; repeat or comma-separate for several
+    #[arg(long = "resolver", value_delimiter = ',')]
+    resolvers: Vec<SocketAddr>,
+    /// Forward names under a domain to their own resolvers, as domain=ip:port[,ip:port...];
+    /// the longest matching domain wins and --resolver handles everything else
+    #[arg(long = "forward-zone", value_parser = parse_forward_zone)]
+    forward_zones: Vec<(DnsName, Vec<SocketAddr>)>,
+    /// How to pick an upstream: failover, round-robin, random or lowest-latency
+    #[arg(long, default_value_t = Strategy::Failover)]
+    upstream_strategy: Strategy,
+    /// Seconds an upstream that timed out or answered SERVFAIL is passed over
+    #[arg(long, default_value_t = 30)]
+    upstream_sideline: u64,
+    /// Milliseconds to wait for each upstream attempt
+    #[arg(long, default_value_t = 2000)]
+    upstream_timeout: u64,
+    /// Attempts per question before answering SERVFAIL
+    #[arg(long, default_value_t = 3)]
+    upstream_attempts: usize,
+    /// Resolve names not served locally from the root down, unless --resolver or --forward-zone
+    /// covers them
+    #[arg(long)]
+    recursive: bool,
+    /// Root server to start recursion from, as ip:port; repeat for several, replacing the
+    /// built-in root hints
+    #[arg(long = "root-hint")]
+    root_hints: Vec<SocketAddr>,
+    /// How deeply lookups of name server addresses may nest while recursing
+    #[arg(long, default_value_t = 6)]
+    max_recursion_depth: usize,
+    /// Upstream queries allowed while recursing for one question
+    #[arg(long, default_value_t = 64)]
+    max_recursion_queries: usize,
+    /// Maximum number of RRsets kept in the cache, 0 disables caching
+    #[arg(long, default_value_t = 10000)]
+    cache_entries: usize,
+    /// Maximum bytes of records kept in the cache, counted in wire format
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    cache_size: usize,
+    /// Seconds a TCP client has to send each query in full, and to take each write of the
+    /// answers, before its connection is closed
+    #[arg(long, default_value_t = 10)]
+    tcp_idle_timeout: u64,
+    /// Maximum number of concurrent TCP connections
+    #[arg(long, default_value_t = 128)]
+    max_tcp_connections: usize,
+    /// Maximum number of UDP queries handled at once
+    #[arg(long, default_value_t = 256)]
+    max_outstanding_queries: usize,
 }
 
-fn resolve_dns_msg(
-    udp_socket: &UdpSocket,
-    upstream_addr: &String,
-    dns_message: DnsMessage,
-) -> DnsMessage {
-    let upstream_replies = dns_message
-        .questions
-        .iter()
-        .map(|question| {
-            let mut header = dns_message.header.clone();
-            header.question_count = 1;
-            header.answer_count = 0;
-            let msg = DnsMessage {
-                header,
-                questions: vec![question.clone()],
-                answers: vec![],
-                authorities: vec![],
-                additional: vec![],
-            };
-            
-            udp_socket
-                .send_to(&msg.as_buf(), upstream_addr)
-                .expect("Failed to send request upstream");
-            
-            let mut forward_buf = [0; 512];
-            udp_socket
-                .recv_from(&mut forward_buf)
-                .expect("Failed to receive response from upstream");
+fn parse_zone_arg(value: &str) -> Result<(DnsName, PathBuf), String> {
+    match value.split_once('=') {
+        Some((origin, path)) => Ok((DnsName::new(origin.to_string()), PathBuf::from(path))),
+        None => Ok((DnsName::new(String::new()), PathBuf::from(value))),
+    }
+}
 
-            return DnsMessage::from(forward_buf);
-        })
-        .collect();
+fn parse_forward_zone(value: &str) -> Result<(DnsName, Vec<SocketAddr>), String> {
+    let (domain, upstreams) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected domain=ip:port, got {value}"))?;
+    let upstreams = upstreams
+        .split(',')
+        .map(|upstream| upstream.parse().map_err(|e| format!("{upstream}: {e}")))
+        .collect::<Result<Vec<SocketAddr>, String>>()?;
+    Ok((DnsName::new(domain.to_string()), upstreams))
+}
 
-    return DnsMessage::merge(upstream_replies);
+fn parse_zone_clients(value: &str) -> Result<(DnsName, Vec<IpAddr>), String> {
+    let (zone, clients) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected zone=ip, got {value}"))?;
+    let clients = clients
+        .split(',')
+        .map(|client| client.parse().map_err(|e| format!("{client}: {e}")))
+        .collect::<Result<Vec<IpAddr>, String>>()?;
+    Ok((DnsName::new(zone.to_string()), clients))
 }
 
 fn main() {
     let args = Args::parse();
-    let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
-    let mut buf = [0; 512];
-    let resolver = args.resolver;
-    
-    loop {
-        match udp_socket.recv_from(&mut buf) {
-            Ok((_, source)) => {
-                // parse stuff
-                let received_message = DnsMessage::from(buf);
-                
-                let response =
-                    resolve_dns_msg(&udp_socket, &resolver, received_message);
-                
-                udp_socket
-                    .send_to(&response.as_buf(), source)
-                    .expect("Failed to send response");
-            }
+    let udp_socket =
+        Arc::new(UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address"));
+    let tcp_listener = TcpListener::bind("127.0.0.1:2053").expect("Failed to bind to address");
+
+    let upstream_set = |upstreams| {
+        UpstreamSet::new(
+            upstreams,
+            args.upstream_strategy,
+            Duration::from_secs(args.upstream_sideline),
+        )
+    };
+    let forwarder = (!args.resolvers.is_empty() || !args.forward_zones.is_empty()).then(|| {
+        let mut forwarder = Forwarder::new(
+            upstream_set(args.resolvers),
+            Duration::from_millis(args.upstream_timeout),
+            args.upstream_attempts,
+        );
+        for (suffix, upstreams) in args.forward_zones {
+            forwarder.add_rule(suffix, upstream_set(upstreams));
+        }
+        forwarder
+    });
+    let resolver = args.recursive.then(|| {
+        let root_hints = match args.root_hints.is_empty() {
+            true => Resolver::default_root_hints(),
+            false => args.root_hints.clone(),
+        };
+        Resolver::new(
+            root_hints,
+            Duration::from_millis(args.upstream_timeout),
+            args.max_recursion_depth,
+            args.max_recursion_queries,
+        )
+    });
+
+    let catalog = Catalog::new();
+    let zone_policy = |origin: &DnsName| ZonePolicy {
+        allow_transfer: args
+            .allow_transfer
+            .iter()
+            .filter(|(name, _)| name == origin)
+            .flat_map(|(_, clients)| clients.iter().copied())
+            .collect(),
+        notify: args
+            .notify
+            .iter()
+            .filter(|(name, _)| name == origin)
+            .flat_map(|(_, targets)| targets.iter().copied())
+            .collect(),
+        allow_update: args
+            .allow_update
+            .iter()
+            .filter(|(name, _)| name == origin)
+            .flat_map(|(_, clients)| clients.iter().copied())
+            .collect(),
+    };
+    let mut zone_files = HashMap::new();
+    for (origin, path) in &args.zones {
+        let mut zone = match Zone::load(path, origin) {
+            Ok(zone) => zone,
             Err(e) => {
-                eprintln!("Error receiving data: {}", e);
-                break;
+                eprintln!("Failed to load zone: {:#}", e);
+                process::exit(1);
             }
+        };
+        zone.policy = zone_policy(&zone.origin);
+        // secondaries may have missed changes made while we were down
+        notify_secondaries(&zone);
+        zone_files.insert(zone.origin.clone(), path.clone());
+        catalog.insert(zone);
+    }
+    let secondaries: Vec<Secondary> = args
+        .secondaries
+        .iter()
+        .map(|(origin, primaries)| {
+            let path = args
+                .secondary_dir
+                .as_ref()
+                .map(|dir| dir.join(format!("{}zone", origin)));
+            let mut secondary = Secondary::new(
+                origin.clone(),
+                primaries.clone(),
+                path,
+                Duration::from_secs(args.transfer_timeout),
+            );
+            secondary.policy = zone_policy(origin);
+            secondary
+        })
+        .collect();
+    let policy_zones = args
+        .allow_transfer
+        .iter()
+        .map(|(name, _)| ("--allow-transfer", name))
+        .chain(args.notify.iter().map(|(name, _)| ("--notify", name)));
+    for (option, name) in policy_zones {
+        let served = catalog.get(name).is_some()
+            || secondaries
+                .iter()
+                .any(|secondary| secondary.origin == *name);
+        if !served {
+            eprintln!("{} names {}, which isn't a served zone", option, name);
+            process::exit(1);
         }
     }
+    // secondary copies only change through transfers from their primaries
+    for (name, _) in &args.allow_update {
+        if catalog.get(name).is_none() {
+            eprintln!("--allow-update names {}, which isn't a zone loaded with --zone", name);
+            process::exit(1);
+        }
+    }
+
+    let cache = Cache::new(args.cache_entries, args.cache_size);
+    let mut server = Server::new(catalog, forwarder, resolver, cache);
+    server.zone_files = zone_files;
+    for secondary in &secondaries {
+        server
+            .secondaries
+            .insert(secondary.origin.clone(), secondary.handle());
+    }
+    let server = Arc::new(server);
+
+    for secondary in secondaries {
+        let server = server.clone();
+        thread::spawn(move || secondary.run(&server.catalog));
+    }
+
+    let tcp_config = TcpConfig {
+        idle_timeout: Duration::from_secs(args.tcp_idle_timeout),
+        max_connections: args.max_tcp_connections,
+    };
+    let tcp_server = server.clone();
+    thread::spawn(move || tcp::serve(tcp_listener, tcp_server, tcp_config));
+
+    udp::serve(udp_socket, server, args.max_outstanding_queries);
 }
@@ -0,0 +1,584 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+
+use crate::{
+    cache::{cname_chain, Cache, CacheData, CacheKey},
+    dns::{
+        answer::DnsAnswer,
+        common::{DnsClass, DnsName, DnsType},
+        header::{DnsHeader, DnsHeaderAA, DnsHeaderRD, DnsHeaderRcode},
+        message::DnsMessage,
+        question::DnsQuestion,
+        rdata::RData,
+    },
+    forwarder::{new_request, query_upstream},
+};
+
+/// IPv4 addresses of a.root-servers.net through m.root-servers.net.
+pub const ROOT_HINTS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// CNAMEs followed for one question before giving up.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Referrals followed for one name, more than enough to walk down 127 labels.
+const MAX_REFERRALS: usize = 128;
+
+/// Resolves questions itself, starting at the root and following referrals down to the servers
+/// authoritative for the name.
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    pub root_hints: Vec<SocketAddr>,
+    // port name servers found along the way are queried on
+    pub port: u16,
+    // how long to wait for each server
+    pub timeout: Duration,
+    // how deeply resolutions may nest to find the addresses of glueless name servers
+    pub max_depth: usize,
+    // upstream queries allowed for one client question
+    pub max_queries: usize,
+}
+
+impl Resolver {
+    pub fn new(
+        root_hints: Vec<SocketAddr>,
+        timeout: Duration,
+        max_depth: usize,
+        max_queries: usize,
+    ) -> Self {
+        Self {
+            root_hints,
+            port: 53,
+            timeout,
+            max_depth,
+            max_queries,
+        }
+    }
+
+    /// The built-in root server addresses.
+    pub fn default_root_hints() -> Vec<SocketAddr> {
+        ROOT_HINTS
+            .iter()
+            .map(|address| SocketAddr::new(IpAddr::V4(*address), 53))
+            .collect()
+    }
+
+    /// Resolves `question` iteratively. Delegations learned on the way are kept in `cache` and
+    /// used as starting points for later questions.
+    pub fn resolve(&self, question: &DnsQuestion, cache: &Cache) -> anyhow::Result<DnsMessage> {
+        let mut resolution = Resolution {
+            resolver: self,
+            cache,
+            queries: 0,
+        };
+        resolution.resolve(question, 0)
+    }
+}
+
+/// State of resolving one client question, which may spawn further resolutions for name
+/// server addresses; they all draw on the same query budget.
+struct Resolution<'a> {
+    resolver: &'a Resolver,
+    cache: &'a Cache,
+    queries: usize,
+}
+
+impl Resolution<'_> {
+    /// Resolves a question, restarting from the closest known servers for each CNAME target.
+    fn resolve(&mut self, question: &DnsQuestion, depth: usize) -> anyhow::Result<DnsMessage> {
+        if depth > self.resolver.max_depth {
+            bail!("Gave up on {} nested too deeply", question.name);
+        }
+
+        let mut answers = vec![];
+        let mut name = question.name.clone();
+        for _ in 0..=MAX_CNAME_CHAIN {
+            let current = DnsQuestion::new(&name.name, question.qtype, question.qclass);
+
+            let (rcode, authorities) = match self.cache.lookup(&current) {
+                Some(cached) => {
+                    answers.extend(cached.answers);
+                    (cached.rcode, cached.authorities)
+                }
+                None => {
+                    let reply = self.query_authoritative(&current, depth)?;
+                    self.cache.insert_reply(&current, &reply);
+
+                    // keep the part of the answer on the chain from this name, and carry on
+                    // from wherever the chain ends if it doesn't reach an answer
+                    let chain = cname_chain(&current, &reply.answers);
+                    let last = chain.last().expect("the chain starts at the question name");
+                    answers.extend(
+                        reply
+                            .answers
+                            .iter()
+                            .filter(|answer| {
+                                chain.contains(&answer.name)
+                                    && (answer.qtype == question.qtype
+                                        || answer.qtype == DnsType::CNAME)
+                            })
+                            .cloned(),
+                    );
+                    let answered = reply
+                        .answers
+                        .iter()
+                        .any(|answer| answer.name == *last && answer.qtype == question.qtype);
+                    if reply.header.rcode == DnsHeaderRcode::NoError && !answered && chain.len() > 1
+                    {
+                        name = last.clone();
+                        continue;
+                    }
+                    (reply.header.rcode, reply.authorities)
+                }
+            };
+
+            let header = DnsHeader::try_from(&[0u8; 12][..]).expect("a zeroed header is valid");
+            let mut response = DnsMessage::new_error_response(&header, rcode);
+            response.questions = vec![question.clone()];
+            response.answers = answers;
+            response.authorities = authorities;
+            return Ok(response);
+        }
+
+        Err(anyhow!("CNAME chain from {} is too long", question.name))
+    }
+
+    /// Walks down from the closest known servers for the name until a server answers for it
+    /// itself, returning that server's reply.
+    fn query_authoritative(
+        &mut self,
+        question: &DnsQuestion,
+        depth: usize,
+    ) -> anyhow::Result<DnsMessage> {
+        let (mut zone, mut servers) = self.closest_servers(&question.name);
+
+        for _ in 0..MAX_REFERRALS {
+            let reply = in_bailiwick(self.query_servers(&servers, question)?, question, &zone);
+            if reply.header.rcode != DnsHeaderRcode::NoError || !reply.answers.is_empty() {
+                return Ok(reply);
+            }
+
+            // a referral names servers for a zone below the one just asked, and above the name
+            let cut = reply
+                .authorities
+                .iter()
+                .find(|record| record.qtype == DnsType::NS && record.qclass == question.qclass)
+                .map(|record| record.name.clone());
+            let cut = match cut {
+                Some(cut)
+                    if cut != zone
+                        && cut.is_subdomain_of(&zone)
+                        && question.name.is_subdomain_of(&cut) =>
+                {
+                    cut
+                }
+                // no referral, so this is the authoritative NODATA
+                _ if reply.header.authoritative_answer == DnsHeaderAA::Authoritative => {
+                    return Ok(reply)
+                }
+                None if reply.authorities.iter().any(|r| r.qtype == DnsType::SOA) => {
+                    return Ok(reply)
+                }
+                _ => bail!(
+                    "Lame referral for {} from servers of {}",
+                    question.name,
+                    zone
+                ),
+            };
+
+            let ns: Vec<DnsAnswer> = reply
+                .authorities
+                .iter()
+                .filter(|record| record.qtype == DnsType::NS && record.name == cut)
+                .cloned()
+                .collect();
+            self.cache.insert(ns.clone());
+            self.cache_glue(&reply, &ns, &zone);
+
+            servers = self.server_addresses(&ns, depth)?;
+            zone = cut;
+        }
+
+        Err(anyhow!("Too many referrals for {}", question.name))
+    }
+
+    /// Caches addresses of the new name servers from the additional section, as long as they're
+    /// within the zone of the server that sent them.
+    fn cache_glue(&self, reply: &DnsMessage, ns: &[DnsAnswer], zone: &DnsName) {
+        let mut rrsets: HashMap<CacheKey, Vec<DnsAnswer>> = HashMap::new();
+        reply
+            .additional
+            .iter()
+            .filter(|record| matches!(record.qtype, DnsType::A | DnsType::AAAA))
+            .filter(|record| record.name.is_subdomain_of(zone))
+            .filter(|record| {
+                ns.iter()
+                    .any(|ns| ns.data == RData::NS(record.name.clone()))
+            })
+            .for_each(|record| {
+                let key = CacheKey::new(record.name.clone(), record.qtype, record.qclass);
+                rrsets.entry(key).or_default().push(record.clone());
+            });
+        rrsets
+            .into_values()
+            .for_each(|records| self.cache.insert(records));
+    }
+
+    /// Servers for the deepest zone above `name` whose name server addresses are cached, or the
+    /// root hints if there is none.
+    fn closest_servers(&self, name: &DnsName) -> (DnsName, Vec<SocketAddr>) {
+        let mut candidate = Some(name.clone());
+        while let Some(zone) = candidate {
+            if zone.label_count() == 0 {
+                break;
+            }
+            let key = CacheKey::new(zone.clone(), DnsType::NS, DnsClass::IN);
+            if let Some(CacheData::Records(ns)) = self.cache.get(&key) {
+                let servers = self.cached_addresses(&ns);
+                if !servers.is_empty() {
+                    return (zone, servers);
+                }
+            }
+            candidate = zone.parent();
+        }
+
+        (
+            DnsName::new(String::new()),
+            self.resolver.root_hints.clone(),
+        )
+    }
+
+    /// Cached addresses of the targets of NS records, IPv4 first.
+    fn cached_addresses(&self, ns: &[DnsAnswer]) -> Vec<SocketAddr> {
+        let mut addresses = vec![];
+        for qtype in [DnsType::A, DnsType::AAAA] {
+            for record in ns {
+                let RData::NS(target) = &record.data else {
+                    continue;
+                };
+                let key = CacheKey::new(target.clone(), qtype, DnsClass::IN);
+                if let Some(CacheData::Records(records)) = self.cache.get(&key) {
+                    addresses.extend(records.iter().filter_map(|record| self.address(record)));
+                }
+            }
+        }
+        addresses
+    }
+
+    fn address(&self, record: &DnsAnswer) -> Option<SocketAddr> {
+        match record.data {
+            RData::A(address) => Some(SocketAddr::new(address.into(), self.resolver.port)),
+            RData::AAAA(address) => Some(SocketAddr::new(address.into(), self.resolver.port)),
+            _ => None,
+        }
+    }
+
+    /// Addresses of the name servers in `ns`, from glue or cache if possible, otherwise by
+    /// resolving the server names one at a time until one of them has an address.
+    fn server_addresses(
+        &mut self,
+        ns: &[DnsAnswer],
+        depth: usize,
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        let addresses = self.cached_addresses(ns);
+        if !addresses.is_empty() {
+            return Ok(addresses);
+        }
+
+        let mut last_error = anyhow!("No name servers listed");
+        for record in ns {
+            let RData::NS(target) = &record.data else {
+                continue;
+            };
+            let question = DnsQuestion::new(&target.name, DnsType::A, DnsClass::IN);
+            match self.resolve(&question, depth + 1) {
+                Ok(reply) => {
+                    let addresses: Vec<SocketAddr> = reply
+                        .answers
+                        .iter()
+                        .filter_map(|record| self.address(record))
+                        .collect();
+                    if !addresses.is_empty() {
+                        return Ok(addresses);
+                    }
+                    last_error = anyhow!("{} has no address", target);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Asks each server in turn until one gives a usable reply, counting every attempt against
+    /// the query budget.
+    fn query_servers(
+        &mut self,
+        servers: &[SocketAddr],
+        question: &DnsQuestion,
+    ) -> anyhow::Result<DnsMessage> {
+        let mut last_error = anyhow!("No servers to ask for {}", question.name);
+
+        for server in servers {
+            if self.queries >= self.resolver.max_queries {
+                bail!(
+                    "Gave up on {} after {} queries",
+                    question.name,
+                    self.queries
+                );
+            }
+            self.queries += 1;
+
+            let request = new_request(question, DnsHeaderRD::RecursionNotDesired, false);
+            match query_upstream(*server, &request, self.resolver.timeout) {
+                Ok(reply)
+                    if matches!(
+                        reply.header.rcode,
+                        DnsHeaderRcode::NoError | DnsHeaderRcode::NameError
+                    ) =>
+                {
+                    return Ok(reply)
+                }
+                Ok(reply) => {
+                    last_error = anyhow!("{} answered {:?}", server, reply.header.rcode);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+/// Drops records outside `zone` from a reply by its servers, which have no say over them and
+/// could otherwise plant them in the cache. A CNAME chain leaving the zone then ends where it
+/// leaves, to be followed with a lookup of its own, and the RCODE and authority records that
+/// went with the target are dropped too.
+fn in_bailiwick(mut reply: DnsMessage, question: &DnsQuestion, zone: &DnsName) -> DnsMessage {
+    reply
+        .answers
+        .retain(|record| record.name.is_subdomain_of(zone));
+    reply
+        .authorities
+        .retain(|record| record.name.is_subdomain_of(zone));
+
+    let chain = cname_chain(question, &reply.answers);
+    let last = chain.last().expect("the chain starts at the question name");
+    if !last.is_subdomain_of(zone) {
+        reply.header.rcode = DnsHeaderRcode::NoError;
+        reply.authorities.clear();
+    }
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        path::Path,
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    use super::Resolver;
+    use crate::{
+        cache::{Cache, CacheKey},
+        dns::{
+            answer::DnsAnswer,
+            common::{DnsClass, DnsName, DnsType},
+            header::{DnsHeaderAA, DnsHeaderQR, DnsHeaderRcode},
+            message::DnsMessage,
+            question::DnsQuestion,
+            rdata::RData,
+        },
+        server::{udp, Server},
+        zone::{catalog::Catalog, parser::parse_str, Zone},
+    };
+
+    /// Answers every question on `addr` with a CNAME to victim.example.net, followed by a
+    /// forged address for it.
+    fn spawn_hostile(addr: SocketAddr) {
+        let socket = UdpSocket::bind(addr).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let mut message = DnsMessage::try_from(&buf[..size]).unwrap();
+                message.header.query_response = DnsHeaderQR::Reply;
+                message.header.authoritative_answer = DnsHeaderAA::Authoritative;
+                let name = message.questions[0].name.name.clone();
+                let target = DnsName::new("victim.example.net".into());
+                message.answers = vec![
+                    DnsAnswer::new(
+                        &name,
+                        DnsType::CNAME,
+                        DnsClass::IN,
+                        60,
+                        RData::CNAME(target),
+                    ),
+                    DnsAnswer::new(
+                        "victim.example.net",
+                        DnsType::A,
+                        DnsClass::IN,
+                        60,
+                        RData::A([203, 0, 113, 66].into()),
+                    ),
+                ];
+                socket.send_to(&message.as_buf(), source).unwrap();
+            }
+        });
+    }
+
+    /// Serves the given zones authoritatively on `addr`.
+    fn spawn_authoritative(addr: SocketAddr, zones: &[(&str, &str)]) {
+        let catalog = Catalog::new();
+        for (origin, text) in zones {
+            let origin = DnsName::new(origin.to_string());
+            let records = parse_str(text, &origin, Path::new(".")).unwrap();
+            catalog.insert(Zone::new(origin, records).unwrap());
+        }
+        let server = Arc::new(Server::new(catalog, None, None, Cache::new(0, 0)));
+        let socket = Arc::new(UdpSocket::bind(addr).unwrap());
+        thread::spawn(move || udp::serve(socket, server, 4));
+    }
+
+    #[test]
+    fn test_resolver_walks_fake_hierarchy() {
+        // root on 127.0.0.2, com on 127.0.0.3, example.com and other.com on 127.0.0.4, all on
+        // the same port
+        let port = UdpSocket::bind("127.0.0.2:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = |last: u8| SocketAddr::from(([127, 0, 0, last], port));
+
+        let soa = "@ SOA ns hostmaster 1 3600 600 86400 300\n";
+        spawn_authoritative(
+            addr(2),
+            &[(
+                "",
+                &format!(
+                    "$TTL 3600\n{soa}@ NS a.root-servers.test.\n\
+                     a.root-servers.test. A 127.0.0.2\n\
+                     com NS ns.com-servers.test.\nns.com-servers.test. A 127.0.0.3\n"
+                ),
+            )],
+        );
+        spawn_authoritative(
+            addr(3),
+            &[(
+                "com",
+                &format!(
+                    "$TTL 3600\n{soa}@ NS ns.com-servers.test.\n\
+                     example NS ns1.example\nns1.example A 127.0.0.4\n\
+                     other NS ns.example.com.\n"
+                ),
+            )],
+        );
+        spawn_authoritative(
+            addr(4),
+            &[
+                (
+                    "example.com",
+                    &format!(
+                        "$TTL 3600\n{soa}@ NS ns1\nns1 A 127.0.0.4\nns A 127.0.0.4\n\
+                         www CNAME host.other.com.\n"
+                    ),
+                ),
+                (
+                    "other.com",
+                    &format!("$TTL 3600\n{soa}@ NS ns.example.com.\nhost A 192.0.2.80\n"),
+                ),
+            ],
+        );
+
+        let mut resolver = Resolver::new(vec![addr(2)], Duration::from_millis(500), 4, 32);
+        resolver.port = port;
+        let cache = Cache::new(256, 65536);
+
+        // other.com's only server has no glue, so its address is resolved first
+        let question = DnsQuestion::new("host.other.com", DnsType::A, DnsClass::IN);
+        let response = resolver.resolve(&question, &cache).unwrap();
+        assert_eq!(response.answers[0].data, RData::A([192, 0, 2, 80].into()));
+        let key = CacheKey::new(DnsName::new("com".into()), DnsType::NS, DnsClass::IN);
+        assert!(cache.get(&key).is_some());
+
+        let question = DnsQuestion::new("www.example.com", DnsType::A, DnsClass::IN);
+        let response = resolver.resolve(&question, &cache).unwrap();
+        assert_eq!(response.answers.len(), 2);
+        assert_eq!(response.answers[0].qtype, DnsType::CNAME);
+        assert_eq!(response.answers[1].data, RData::A([192, 0, 2, 80].into()));
+
+        let question = DnsQuestion::new("missing.example.com", DnsType::A, DnsClass::IN);
+        let response = resolver.resolve(&question, &cache).unwrap();
+        assert_eq!(response.header.rcode, DnsHeaderRcode::NameError);
+        assert_eq!(response.authorities[0].qtype, DnsType::SOA);
+
+        // starting from scratch, the walk down takes more than two queries
+        resolver.max_queries = 2;
+        let question = DnsQuestion::new("ns1.example.com", DnsType::A, DnsClass::IN);
+        assert!(resolver
+            .resolve(&question, &Cache::new(256, 65536))
+            .is_err());
+    }
+
+    #[test]
+    fn test_resolver_chases_out_of_zone_cname_targets() {
+        // root on 127.0.0.2 delegates evil to 127.0.0.3, which vouches for a name outside it
+        let port = UdpSocket::bind("127.0.0.2:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = |last: u8| SocketAddr::from(([127, 0, 0, last], port));
+
+        let soa = "@ SOA ns hostmaster 1 3600 600 86400 300\n";
+        spawn_authoritative(
+            addr(2),
+            &[(
+                "",
+                &format!(
+                    "$TTL 3600\n{soa}@ NS a.root-servers.test.\n\
+                     a.root-servers.test. A 127.0.0.2\n\
+                     evil NS ns.evil.\nns.evil. A 127.0.0.3\n\
+                     victim.example.net. A 192.0.2.80\n"
+                ),
+            )],
+        );
+        spawn_hostile(addr(3));
+
+        let mut resolver = Resolver::new(vec![addr(2)], Duration::from_millis(500), 4, 32);
+        resolver.port = port;
+        let cache = Cache::new(256, 65536);
+
+        // the target is looked up from the root rather than taken from evil's servers
+        let question = DnsQuestion::new("www.evil", DnsType::A, DnsClass::IN);
+        let response = resolver.resolve(&question, &cache).unwrap();
+        assert_eq!(response.answers.len(), 2);
+        assert_eq!(response.answers[0].qtype, DnsType::CNAME);
+        assert_eq!(response.answers[1].data, RData::A([192, 0, 2, 80].into()));
+
+        let question = DnsQuestion::new("victim.example.net", DnsType::A, DnsClass::IN);
+        let cached = cache.lookup(&question).unwrap();
+        assert_eq!(cached.answers.len(), 1);
+        assert_eq!(cached.answers[0].data, RData::A([192, 0, 2, 80].into()));
+    }
+}
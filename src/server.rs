@@ -0,0 +1,312 @@
+pub mod pool;
+pub mod tcp;
+pub mod transfer;
+pub mod udp;
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{
+    cache::Cache,
+    dns::{
+        common::{DnsName, DnsType},
+        edns::{Edns, EDNS_FLAG_DO, EXTENDED_RCODE_BADVERS},
+        header::{
+            DnsHeader, DnsHeaderAA, DnsHeaderAD, DnsHeaderCD, DnsHeaderOpcode, DnsHeaderQR,
+            DnsHeaderRA, DnsHeaderRcode,
+        },
+        message::DnsMessage,
+        question::DnsQuestion,
+    },
+    forwarder::Forwarder,
+    resolver::Resolver,
+    zone::{catalog::Catalog, notify::notify_secondaries, secondary::SecondaryHandle, update},
+};
+
+/// UDP payload size we advertise to clients.
+pub const MAX_UDP_PAYLOAD_SIZE: u16 = 4096;
+
+/// Everything needed to answer queries, shared by the UDP and TCP transports.
+#[derive(Debug)]
+pub struct Server {
+    // zones answered authoritatively
+    pub catalog: Catalog,
+    // everything else is forwarded, or resolved from the root if there's nowhere to forward
+    // to, or refused if neither is set up
+    pub forwarder: Option<Forwarder>,
+    pub resolver: Option<Resolver>,
+    pub cache: Cache,
+    // zones we're a secondary for, to be checked when their primaries send a NOTIFY
+    pub secondaries: HashMap<DnsName, SecondaryHandle>,
+    // master files of zones loaded from disk, which dynamic updates are written back to
+    pub zone_files: HashMap<DnsName, PathBuf>,
+    // held while an update is checked and applied, so concurrent ones don't undo each other
+    updates: Mutex<()>,
+}
+
+impl Server {
+    pub fn new(
+        catalog: Catalog,
+        forwarder: Option<Forwarder>,
+        resolver: Option<Resolver>,
+        cache: Cache,
+    ) -> Self {
+        Self {
+            catalog,
+            forwarder,
+            resolver,
+            cache,
+            secondaries: HashMap::new(),
+            zone_files: HashMap::new(),
+            updates: Mutex::new(()),
+        }
+    }
+
+    /// Answers a parsed query from `client`, whichever transport it arrived on. Replies get
+    /// nothing back, or two servers (or one spoofed packet) could bounce them back and forth.
+    pub fn handle_query(&self, query: &DnsMessage, client: IpAddr) -> Option<DnsMessage> {
+        if query.header.query_response == DnsHeaderQR::Reply {
+            return None;
+        }
+        let mut response = self.respond(query, client);
+        response.header.recursion_available = self.recursion_available();
+        Some(response)
+    }
+
+    /// RA for our responses: set if names outside our own zones get forwarded or resolved rather
+    /// than refused, whatever the response is to.
+    pub fn recursion_available(&self) -> DnsHeaderRA {
+        match self.forwarder.is_some() || self.resolver.is_some() {
+            true => DnsHeaderRA::RecursionAvailable,
+            false => DnsHeaderRA::RecursionNotAvailable,
+        }
+    }
+
+    fn respond(&self, query: &DnsMessage, client: IpAddr) -> DnsMessage {
+        match query.header.opcode {
+            DnsHeaderOpcode::Query => {}
+            DnsHeaderOpcode::Notify => return self.handle_notify(query, client),
+            DnsHeaderOpcode::Update => return self.handle_update(query, client),
+            _ => {
+                return DnsMessage::new_error_response(
+                    &query.header,
+                    DnsHeaderRcode::NotImplemented,
+                )
+            }
+        }
+        if query.questions.is_empty() {
+            return DnsMessage::new_error_response(&query.header, DnsHeaderRcode::FormatError);
+        }
+        // transfers depend on who is asking, so the transports hand them to `transfer` instead
+        if transfer::is_transfer(query) {
+            let mut response =
+                DnsMessage::new_error_response(&query.header, DnsHeaderRcode::NotImplemented);
+            response.questions = query.questions.clone();
+            return response;
+        }
+
+        // only EDNS version 0 exists, anything newer gets BADVERS (RFC 6891 section 6.1.3)
+        if query.edns.as_ref().is_some_and(|edns| edns.version > 0) {
+            let mut response =
+                DnsMessage::new_error_response(&query.header, DnsHeaderRcode::NoError);
+            let mut edns = Edns::new(MAX_UDP_PAYLOAD_SIZE);
+            edns.extended_rcode = (EXTENDED_RCODE_BADVERS >> 4) as u8;
+            response.edns = Some(edns);
+            return response;
+        }
+
+        let mut response = match self.resolve(query) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Error resolving query: {:#}", e);
+                DnsMessage::new_error_response(&query.header, DnsHeaderRcode::ServerFailure)
+            }
+        };
+
+        // only answer with OPT if the client sent one, keeping upstream's extended RCODE and DO
+        // bit
+        response.edns = query.edns.as_ref().map(|_| {
+            let mut edns = Edns::new(MAX_UDP_PAYLOAD_SIZE);
+            if let Some(upstream_edns) = &response.edns {
+                edns.extended_rcode = upstream_edns.extended_rcode;
+                edns.flags = upstream_edns.flags & EDNS_FLAG_DO;
+            }
+            edns
+        });
+        response
+    }
+
+    /// Acknowledges a NOTIFY from one of a secondary zone's primaries and has the zone checked
+    /// (RFC 1996 section 3.7). Anyone else, or a zone we aren't a secondary for, is refused.
+    fn handle_notify(&self, query: &DnsMessage, client: IpAddr) -> DnsMessage {
+        let mut response = DnsMessage::new_error_response(&query.header, DnsHeaderRcode::NoError);
+        response.questions = query.questions.clone();
+
+        let [question] = &query.questions[..] else {
+            response.header.rcode = DnsHeaderRcode::FormatError;
+            return response;
+        };
+        if question.qtype != DnsType::SOA {
+            response.header.rcode = DnsHeaderRcode::NotImplemented;
+            return response;
+        }
+        match self.secondaries.get(&question.name) {
+            Some(secondary) if secondary.primaries.contains(&client) => {
+                secondary.notify();
+                response.header.authoritative_answer = DnsHeaderAA::Authoritative;
+            }
+            _ => {
+                eprintln!("Refused NOTIFY for {} from {}", question.name, client);
+                response.header.rcode = DnsHeaderRcode::Refused;
+            }
+        }
+        response
+    }
+
+    /// Applies a dynamic update from a client allowed to make one to a zone we serve (RFC 2136
+    /// section 3). Secondary copies only change through transfers, so they refuse updates too.
+    /// The new version is saved to the zone's file before it's served, and if that fails the
+    /// update gets SERVFAIL and the old version stays (RFC 2136 section 3.5).
+    fn handle_update(&self, request: &DnsMessage, client: IpAddr) -> DnsMessage {
+        let mut response = DnsMessage::new_error_response(&request.header, DnsHeaderRcode::NoError);
+        response.questions = request.questions.clone();
+
+        // the zone section has the update's zone as its only entry
+        let [zone_section] = &request.questions[..] else {
+            response.header.rcode = DnsHeaderRcode::FormatError;
+            return response;
+        };
+        if zone_section.qtype != DnsType::SOA {
+            response.header.rcode = DnsHeaderRcode::FormatError;
+            return response;
+        }
+
+        // the lock guards no data, so one poisoned by an update that panicked is still good
+        let _updating = self.updates.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(zone) = self
+            .catalog
+            .get(&zone_section.name)
+            .filter(|zone| zone.class == zone_section.qclass)
+        else {
+            response.header.rcode = DnsHeaderRcode::NotAuth;
+            return response;
+        };
+        if !zone.policy.allow_update.contains(&client) {
+            eprintln!("Refused update of {} from {}", zone.origin, client);
+            response.header.rcode = DnsHeaderRcode::Refused;
+            return response;
+        }
+
+        match update::update(&zone, request) {
+            Ok(Some(updated)) => {
+                if let Some(path) = self.zone_files.get(&zone.origin) {
+                    if let Err(e) = updated.save(path) {
+                        eprintln!("Failed to save zone {}: {:#}", zone.origin, e);
+                        response.header.rcode = DnsHeaderRcode::ServerFailure;
+                        return response;
+                    }
+                }
+                // installed first, so secondaries checking in on the NOTIFY see the new serial
+                self.catalog.insert(updated);
+                if let Some(updated) = self.catalog.get(&zone.origin) {
+                    notify_secondaries(&updated);
+                }
+            }
+            Ok(None) => {}
+            Err(rcode) => response.header.rcode = rcode,
+        }
+        response
+    }
+
+    /// Resolves each question separately and merges the results into one response.
+    fn resolve(&self, query: &DnsMessage) -> anyhow::Result<DnsMessage> {
+        let dnssec_ok = query.edns.as_ref().is_some_and(Edns::dnssec_ok);
+        let replies = query
+            .questions
+            .iter()
+            .map(|question| self.resolve_question(question, &query.header, dnssec_ok))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let authenticated = replies
+            .iter()
+            .all(|reply| reply.header.authentic_data == DnsHeaderAD::Authenticated);
+
+        let mut response = DnsMessage::merge(replies);
+        response.header.id = query.header.id;
+        response.header.recursion_desired = query.header.recursion_desired;
+        response.header.checking_disabled = query.header.checking_disabled;
+        // AD is passed on when every answer had it, to clients that asked for it with AD or DO
+        // (RFC 6840 section 5.7)
+        let wants_ad = dnssec_ok || query.header.authentic_data == DnsHeaderAD::Authenticated;
+        response.header.authentic_data = match authenticated && wants_ad {
+            true => DnsHeaderAD::Authenticated,
+            false => DnsHeaderAD::NotAuthenticated,
+        };
+        Ok(response)
+    }
+
+    /// Answers one question from our own zones, or else from the cache or by forwarding or
+    /// resolving it and caching the reply. Queries with the DO bit skip the cache, which holds no
+    /// signatures, and so do queries with CD, whose replies may not have been validated.
+    fn resolve_question(
+        &self,
+        question: &DnsQuestion,
+        query: &DnsHeader,
+        dnssec_ok: bool,
+    ) -> anyhow::Result<DnsMessage> {
+        // ID, RD and CD are filled in from the query once all questions are answered
+        let header = DnsHeader::try_from(&[0u8; 12][..]).expect("a zeroed header is valid");
+
+        if let Some(zone) = self.catalog.find(&question.name) {
+            if zone.class == question.qclass {
+                // an expired copy can't be trusted, and nobody else is asked about our zones
+                if zone.expired {
+                    let mut reply =
+                        DnsMessage::new_error_response(&header, DnsHeaderRcode::ServerFailure);
+                    reply.questions = vec![question.clone()];
+                    return Ok(reply);
+                }
+                return Ok(zone.answer(question));
+            }
+        }
+
+        let forwarder = self
+            .forwarder
+            .as_ref()
+            .filter(|forwarder| forwarder.covers(&question.name));
+        if forwarder.is_none() && self.resolver.is_none() {
+            let mut reply = DnsMessage::new_error_response(&header, DnsHeaderRcode::Refused);
+            reply.questions = vec![question.clone()];
+            return Ok(reply);
+        }
+
+        let use_cache = !dnssec_ok && query.checking_disabled == DnsHeaderCD::CheckingEnabled;
+        if use_cache {
+            if let Some(cached) = self.cache.lookup(question) {
+                let mut reply = DnsMessage::new_error_response(&header, cached.rcode);
+                reply.questions = vec![question.clone()];
+                reply.answers = cached.answers;
+                reply.authorities = cached.authorities;
+                return Ok(reply);
+            }
+        }
+
+        let reply = match (forwarder, &self.resolver) {
+            (Some(forwarder), _) => forwarder.exchange(
+                question,
+                query.recursion_desired,
+                query.checking_disabled,
+                dnssec_ok,
+            )?,
+            (None, Some(resolver)) => resolver.resolve(question, &self.cache)?,
+            (None, None) => unreachable!("refused above"),
+        };
+        if query.checking_disabled == DnsHeaderCD::CheckingEnabled {
+            self.cache.insert_reply(question, &reply);
+        }
+        Ok(reply)
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads. Jobs are handed over directly, so at most `workers` jobs are
+/// in flight and `execute` blocks until a worker is free. A job that panics takes only itself
+/// down, never its worker.
+pub struct WorkerPool {
+    sender: SyncSender<Job>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(0);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                // the lock is only held while waiting for the next job, not while running it
+                let job = match receiver.lock() {
+                    Ok(receiver) => receiver.recv(),
+                    Err(_) => return,
+                };
+                match job {
+                    Ok(job) => {
+                        // the panic message itself has already gone to stderr through the hook
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            eprintln!("Worker job panicked, carrying on with the next one");
+                        }
+                    }
+                    Err(_) => return,
+                }
+            });
+        }
+
+        Self { sender }
+    }
+
+    /// Runs `job` on the next free worker, waiting for one if they are all busy.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.sender.send(Box::new(job)).is_err() {
+            eprintln!("Worker pool has shut down");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::WorkerPool;
+
+    #[test]
+    fn test_pool_survives_panicking_jobs() {
+        // with a single worker, the second job only runs if the first didn't kill it
+        let pool = WorkerPool::new(1);
+        pool.execute(|| panic!("job failed"));
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        receiver.recv_timeout(Duration::from_secs(2)).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::dns::{
+    header::DnsHeaderQR,
+    message::{DnsMessage, MAX_MESSAGE_SIZE},
+};
+
+use super::{transfer, Server};
+
+/// Limits applied to TCP clients.
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    // clients get this long to send each query in full, and to take each write of the answers,
+    // or their connection is closed
+    pub idle_timeout: Duration,
+    // connections accepted beyond this are closed straight away
+    pub max_connections: usize,
+}
+
+/// One connection's share of the active connection count, handed back when dropped so that a
+/// connection thread that panics doesn't hold on to it.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Takes a slot, or `None` if all `max` are in use.
+    fn take(active: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        // the slot exists before counting, so a refused one is counted back out on drop
+        let slot = Self(active.clone());
+        (active.fetch_add(1, Ordering::SeqCst) < max).then_some(slot)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts connections on `listener` and serves each on its own thread until accepting fails.
+pub fn serve(listener: TcpListener, server: Arc<Server>, config: TcpConfig) {
+    let active_connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+
+        let Some(slot) = ConnectionSlot::take(&active_connections, config.max_connections) else {
+            continue;
+        };
+
+        let server = server.clone();
+        let idle_timeout = config.idle_timeout;
+        thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = handle_connection(stream, &server, idle_timeout) {
+                eprintln!("TCP connection error: {}", e);
+            }
+        });
+    }
+}
+
+/// Reads the 2-byte length prefixed messages a client sends (RFC 1035 section 4.2.2), possibly
+/// several back to back, and answers each in order until the client closes, goes idle or stops
+/// taking the answers.
+fn handle_connection(
+    mut stream: TcpStream,
+    server: &Server,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    let client = stream.peer_addr()?.ip();
+    // a client that stops reading would otherwise hold the thread and its slot once the socket
+    // buffers fill up; the write fails instead and the connection is dropped
+    stream.set_write_timeout(Some(idle_timeout))?;
+
+    loop {
+        // the whole message has to arrive in time, so trickling it in byte by byte doesn't keep
+        // the connection open
+        let deadline = Instant::now() + idle_timeout;
+        let mut length = [0u8; 2];
+        match read_before(&mut stream, &mut length, deadline) {
+            Ok(()) => {}
+            // the client closed the connection or stopped sending, either way we're done
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::UnexpectedEof
+                        | io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+
+        let mut buf = vec![0; u16::from_be_bytes(length) as usize];
+        read_before(&mut stream, &mut buf, deadline)?;
+
+        let responses = match DnsMessage::try_from(&buf[..]) {
+            // replies are dropped rather than answered, see `Server::handle_query`
+            Ok(query) if query.header.query_response == DnsHeaderQR::Reply => continue,
+            Ok(query) if transfer::is_transfer(&query) => transfer::respond(server, &query, client),
+            Ok(query) => server.handle_query(&query, client).into_iter().collect(),
+            Err(e) => {
+                eprintln!("Malformed query from {}: {}", client, e);
+                match DnsMessage::new_format_error(&buf) {
+                    Some(mut response) => {
+                        response.header.recursion_available = server.recursion_available();
+                        vec![response]
+                    }
+                    None => continue,
+                }
+            }
+        };
+
+        for mut response in responses {
+            let response_buf = response.truncate_to(MAX_MESSAGE_SIZE);
+            let mut framed = Vec::with_capacity(response_buf.len() + 2);
+            framed.extend((response_buf.len() as u16).to_be_bytes());
+            framed.extend(response_buf);
+            stream.write_all(&framed)?;
+        }
+    }
+}
+
+/// Fills `buf` from `stream`, failing with `TimedOut` once `deadline` passes, however much has
+/// arrived by then.
+pub(crate) fn read_before(
+    stream: &mut TcpStream,
+    buf: &mut [u8],
+    deadline: Instant,
+) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{Ipv4Addr, TcpListener, TcpStream, UdpSocket},
+        path::Path,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{serve, TcpConfig};
+    use crate::{
+        cache::Cache,
+        dns::{
+            answer::DnsAnswer,
+            common::{DnsClass, DnsName, DnsType},
+            header::{DnsHeader, DnsHeaderQR, DnsHeaderRA},
+            message::DnsMessage,
+            question::DnsQuestion,
+            rdata::RData,
+        },
+        forwarder::{
+            upstream::{Strategy, UpstreamSet},
+            Forwarder,
+        },
+        server::Server,
+        zone::{catalog::Catalog, parser::parse_str, Zone},
+    };
+
+    fn query(id: u16, name: &str) -> Vec<u8> {
+        let mut header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        header.id = id;
+        let question = DnsQuestion::new(name, DnsType::A, DnsClass::IN);
+        let buf = DnsMessage::new(header, vec![question], vec![], vec![], vec![]).as_buf();
+
+        let mut framed = (buf.len() as u16).to_be_bytes().to_vec();
+        framed.extend(buf);
+        framed
+    }
+
+    #[test]
+    fn test_tcp_answers_pipelined_queries() {
+        // upstream that answers every question with 192.0.2.1
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok((size, source)) = upstream.recv_from(&mut buf) {
+                let mut message = DnsMessage::try_from(&buf[..size]).unwrap();
+                message.header.query_response = DnsHeaderQR::Reply;
+                let name = message.questions[0].name.name.clone();
+                let rdata = RData::A(Ipv4Addr::new(192, 0, 2, 1));
+                message.answers = vec![DnsAnswer::new(&name, DnsType::A, DnsClass::IN, 60, rdata)];
+                upstream.send_to(&message.as_buf(), source).unwrap();
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = TcpConfig {
+            idle_timeout: Duration::from_secs(1),
+            max_connections: 4,
+        };
+        let forwarder = Forwarder::new(
+            UpstreamSet::new(vec![upstream_addr], Strategy::Failover, Duration::ZERO),
+            Duration::from_secs(1),
+            1,
+        );
+        let server = Server::new(Catalog::new(), Some(forwarder), None, Cache::new(16, 4096));
+        thread::spawn(move || serve(listener, server.into(), config));
+
+        // both queries go out before either response is read
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut queries = query(1, "one.example.com");
+        queries.extend(query(2, "two.example.com"));
+        stream.write_all(&queries).unwrap();
+
+        for (id, name) in [(1, "one.example.com"), (2, "two.example.com")] {
+            let mut length = [0u8; 2];
+            stream.read_exact(&mut length).unwrap();
+            let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut buf).unwrap();
+
+            let response = DnsMessage::try_from(&buf[..]).unwrap();
+            assert_eq!(response.header.id, id);
+            assert_eq!(response.answers[0].name.name, name);
+            assert_eq!(
+                response.header.recursion_available,
+                DnsHeaderRA::RecursionAvailable
+            );
+        }
+    }
+
+    #[test]
+    fn test_tcp_closes_trickling_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = TcpConfig {
+            idle_timeout: Duration::from_millis(300),
+            max_connections: 4,
+        };
+        let server = Server::new(Catalog::new(), None, None, Cache::new(16, 4096));
+        thread::spawn(move || serve(listener, server.into(), config));
+
+        // each byte comes well within the timeout, but the query as a whole takes far longer
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        thread::spawn(move || {
+            for byte in query(1, "example.com") {
+                if writer.write_all(&[byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+
+        let start = Instant::now();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .unwrap();
+        let mut buf = [0u8; 512];
+        match stream.read(&mut buf) {
+            Ok(read) => assert_eq!(read, 0),
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_tcp_drops_clients_that_stop_reading() {
+        // an RRset that fills most of a TCP message
+        let mut text = "$TTL 300\n@ SOA ns1 hostmaster 1 3600 600 86400 60\n  NS ns1\n".to_string();
+        for i in 0..700 {
+            text.push_str(&format!("big TXT \"{i} {}\"\n", "x".repeat(60)));
+        }
+        let origin = DnsName::new("example.com".into());
+        let zone = Zone::new(
+            origin.clone(),
+            parse_str(&text, &origin, Path::new(".")).unwrap(),
+        )
+        .unwrap();
+        let catalog = Catalog::new();
+        catalog.insert(zone);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = TcpConfig {
+            idle_timeout: Duration::from_millis(500),
+            max_connections: 1,
+        };
+        let server = Server::new(catalog, None, None, Cache::new(16, 4096));
+        thread::spawn(move || serve(listener, server.into(), config));
+
+        // far more answers than the socket buffers hold, none of which are ever read
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        let header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        let question = DnsQuestion::new("big.example.com", DnsType::TXT, DnsClass::IN);
+        let buf = DnsMessage::new(header, vec![question], vec![], vec![], vec![]).as_buf();
+        let mut queries = vec![];
+        for _ in 0..500 {
+            queries.extend((buf.len() as u16).to_be_bytes());
+            queries.extend(&buf);
+        }
+        stalled.write_all(&queries).unwrap();
+
+        // the only slot comes free once a write to the stalled client times out
+        let start = Instant::now();
+        let answered = loop {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(100));
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+            if stream.write_all(&query(1, "ns1.example.com")).is_err() {
+                continue;
+            }
+            let mut length = [0u8; 2];
+            if stream.read_exact(&mut length).is_ok() {
+                break length;
+            }
+        };
+        assert!(u16::from_be_bytes(answered) > 0);
+        drop(stalled);
+    }
+}
@@ -0,0 +1,374 @@
+use std::{iter, net::IpAddr};
+
+use crate::{
+    dns::{
+        answer::DnsAnswer,
+        common::DnsType,
+        encoder::DnsEncoder,
+        header::{DnsHeaderAA, DnsHeaderOpcode, DnsHeaderRcode},
+        message::{DnsMessage, MAX_MESSAGE_SIZE},
+        question::DnsQuestion,
+        rdata::RData,
+    },
+    zone::{serial_is_newer, Zone},
+};
+
+use super::Server;
+
+/// Whether `query` asks for a zone transfer, which takes a stream of messages to answer.
+pub fn is_transfer(query: &DnsMessage) -> bool {
+    query.header.opcode == DnsHeaderOpcode::Query
+        && query
+            .questions
+            .iter()
+            .any(|question| matches!(question.qtype, DnsType::AXFR | DnsType::IXFR))
+}
+
+/// Answers a transfer request from `client` with the messages to send back, in order, or a single
+/// error. AXFR gets the whole zone between two copies of its SOA (RFC 5936 section 2.2); IXFR gets
+/// the journaled changes since the client's serial, or the whole zone the same way if the journal
+/// doesn't go back that far (RFC 1995 section 4).
+pub fn respond(server: &Server, query: &DnsMessage, client: IpAddr) -> Vec<DnsMessage> {
+    let mut messages = transfer(server, query, client);
+    for message in &mut messages {
+        message.header.recursion_available = server.recursion_available();
+    }
+    messages
+}
+
+fn transfer(server: &Server, query: &DnsMessage, client: IpAddr) -> Vec<DnsMessage> {
+    let error = |rcode| {
+        let mut response = DnsMessage::new_error_response(&query.header, rcode);
+        response.questions = query.questions.clone();
+        vec![response]
+    };
+
+    let [question] = &query.questions[..] else {
+        return error(DnsHeaderRcode::FormatError);
+    };
+    let Some(zone) = server
+        .catalog
+        .get(&question.name)
+        .filter(|zone| zone.class == question.qclass)
+    else {
+        return error(DnsHeaderRcode::Refused);
+    };
+    if !zone.policy.allow_transfer.contains(&client) {
+        eprintln!("Refused transfer of {} to {}", zone.origin, client);
+        return error(DnsHeaderRcode::Refused);
+    }
+    if zone.expired {
+        return error(DnsHeaderRcode::ServerFailure);
+    }
+
+    let records = match question.qtype {
+        DnsType::IXFR => {
+            // the client says which version it has with its SOA in the authority section
+            let Some(serial) = query
+                .authorities
+                .iter()
+                .find_map(|record| match &record.data {
+                    RData::SOA(soa) => Some(soa.serial),
+                    _ => None,
+                })
+            else {
+                return error(DnsHeaderRcode::FormatError);
+            };
+            changes_since(&zone, serial)
+        }
+        _ => full_zone(&zone),
+    };
+    pack(query, records.iter())
+}
+
+/// Answers a transfer request that came over UDP, which only IXFR may (RFC 1995 section 2). If
+/// the reply doesn't fit in `max_size`, the client gets our SOA alone, telling it to ask again
+/// over TCP.
+pub fn respond_udp(
+    server: &Server,
+    query: &DnsMessage,
+    client: IpAddr,
+    max_size: usize,
+) -> DnsMessage {
+    if query
+        .questions
+        .iter()
+        .any(|question| question.qtype == DnsType::AXFR)
+    {
+        let mut response =
+            DnsMessage::new_error_response(&query.header, DnsHeaderRcode::NotImplemented);
+        response.header.recursion_available = server.recursion_available();
+        response.questions = query.questions.clone();
+        return response;
+    }
+
+    let mut messages = respond(server, query, client);
+    let mut response = messages.swap_remove(0);
+    if !messages.is_empty() || response.as_buf().len() > max_size {
+        // every transfer starts with the current SOA
+        response.answers.truncate(1);
+    }
+    response
+}
+
+fn full_zone(zone: &Zone) -> Vec<DnsAnswer> {
+    zone.records()
+        .chain(iter::once(zone.soa_record()))
+        .cloned()
+        .collect()
+}
+
+/// The IXFR reply for a client holding `serial`: our SOA alone if it's up to date, each change
+/// since as old SOA, deletions, new SOA, additions between two copies of our SOA, or else the
+/// whole zone.
+fn changes_since(zone: &Zone, serial: u32) -> Vec<DnsAnswer> {
+    let soa = zone.soa_record();
+    if !serial_is_newer(zone.serial(), serial) {
+        return vec![soa.clone()];
+    }
+    let Some(diffs) = zone.changes_since(serial) else {
+        return full_zone(zone);
+    };
+
+    let mut records = vec![soa.clone()];
+    for diff in diffs {
+        records.push(diff.old_soa.clone());
+        records.extend(diff.deleted.iter().cloned());
+        records.push(diff.new_soa.clone());
+        records.extend(diff.added.iter().cloned());
+    }
+    records.push(soa.clone());
+    records
+}
+
+/// Spreads `records` over as few messages as fit them, each within the 64 KiB a TCP length
+/// prefix allows. Only the first message repeats the question.
+fn pack<'a>(query: &DnsMessage, records: impl Iterator<Item = &'a DnsAnswer>) -> Vec<DnsMessage> {
+    let start = |questions: Vec<DnsQuestion>| {
+        let mut message = DnsMessage::new_error_response(&query.header, DnsHeaderRcode::NoError);
+        message.header.authoritative_answer = DnsHeaderAA::Authoritative;
+        message.questions = questions;
+
+        let mut encoder = DnsEncoder::new(true);
+        encoder.buf().extend(message.header.as_buf());
+        for question in &message.questions {
+            question.write(&mut encoder);
+        }
+        (message, encoder)
+    };
+
+    let mut messages = vec![];
+    let (mut message, mut encoder) = start(query.questions.clone());
+    for record in records {
+        // the encoder holds exactly what the message does, compressed the same way, so its
+        // length is what the message will take on the wire
+        record.write(&mut encoder);
+        if encoder.len() > MAX_MESSAGE_SIZE && !message.answers.is_empty() {
+            messages.push(message);
+            (message, encoder) = start(vec![]);
+            record.write(&mut encoder);
+        }
+        message.answers.push(record.clone());
+    }
+    messages.push(message);
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{IpAddr, Ipv4Addr, TcpListener, TcpStream},
+        path::Path,
+        thread,
+        time::Duration,
+    };
+
+    use super::{respond, respond_udp};
+    use crate::{
+        cache::Cache,
+        dns::{
+            common::{DnsClass, DnsName, DnsType},
+            header::{DnsHeader, DnsHeaderRA, DnsHeaderRcode},
+            message::{DnsMessage, MAX_MESSAGE_SIZE},
+            question::DnsQuestion,
+            rdata::RData,
+        },
+        server::{
+            tcp::{serve, TcpConfig},
+            Server,
+        },
+        zone::{catalog::Catalog, parser::parse_str, Zone},
+    };
+
+    #[test]
+    fn test_axfr_streams_zone_between_soas() {
+        // enough data that the zone can't go in a single message
+        let mut text = "$TTL 300\n@ SOA ns1 hostmaster 7 3600 600 86400 60\n  NS ns1\n".to_string();
+        for i in 0..2000 {
+            text.push_str(&format!("host{i} TXT \"{}\"\n", "x".repeat(40)));
+        }
+        let origin = DnsName::new("example.com".into());
+        let mut zone = Zone::new(
+            origin.clone(),
+            parse_str(&text, &origin, Path::new(".")).unwrap(),
+        )
+        .unwrap();
+        zone.policy.allow_transfer = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        let catalog = Catalog::new();
+        catalog.insert(zone);
+        let server = Server::new(catalog, None, None, Cache::new(16, 4096));
+
+        let mut header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        header.id = 77;
+        let question = DnsQuestion::new("example.com", DnsType::AXFR, DnsClass::IN);
+        let query = DnsMessage::new(header, vec![question], vec![], vec![], vec![]);
+
+        // anyone not on the allow-list is refused
+        let responses = respond(&server, &query, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].header.rcode, DnsHeaderRcode::Refused);
+        assert_eq!(
+            responses[0].header.recursion_available,
+            DnsHeaderRA::RecursionNotAvailable
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = TcpConfig {
+            idle_timeout: Duration::from_secs(1),
+            max_connections: 4,
+        };
+        thread::spawn(move || serve(listener, server.into(), config));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let buf = query.as_buf();
+        stream.write_all(&(buf.len() as u16).to_be_bytes()).unwrap();
+        stream.write_all(&buf).unwrap();
+
+        let mut records = vec![];
+        let mut messages = 0;
+        while records.len() < 2 || records.last().map(|(_, qtype)| *qtype) != Some(DnsType::SOA) {
+            let mut length = [0u8; 2];
+            stream.read_exact(&mut length).unwrap();
+            let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut buf).unwrap();
+            assert!(buf.len() <= MAX_MESSAGE_SIZE);
+
+            let response = DnsMessage::try_from(&buf[..]).unwrap();
+            assert_eq!(response.header.id, 77);
+            assert_eq!(response.header.rcode, DnsHeaderRcode::NoError);
+            assert_eq!(response.questions.len(), usize::from(messages == 0));
+            records.extend(
+                response
+                    .answers
+                    .into_iter()
+                    .map(|answer| (answer.name, answer.qtype)),
+            );
+            messages += 1;
+        }
+
+        assert!(messages > 1);
+        assert_eq!(records.len(), 2003);
+        assert_eq!(records[0], (origin, DnsType::SOA));
+        assert_eq!(
+            records
+                .iter()
+                .filter(|(_, qtype)| *qtype == DnsType::SOA)
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_ixfr_serves_journaled_changes() {
+        let origin = DnsName::new("example.com".into());
+        let version = |serial: u32, www: &str| {
+            let text = format!(
+                "$TTL 300\n@ SOA ns1 hostmaster {serial} 3600 600 86400 60\n  NS ns1\n{www}"
+            );
+            Zone::new(
+                origin.clone(),
+                parse_str(&text, &origin, Path::new(".")).unwrap(),
+            )
+            .unwrap()
+        };
+
+        // 1 -> 2 adds www, 2 -> 3 changes its address
+        let v1 = version(1, "");
+        let v2 = version(2, "www A 192.0.2.10\n").with_history(&v1);
+        let v2 = v1.apply(v2.changes_since(1).unwrap()).unwrap();
+        let mut v3 = version(3, "www A 192.0.2.11\n").with_history(&v2);
+        v3.policy.allow_transfer = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        let catalog = Catalog::new();
+        catalog.insert(v3);
+        let server = Server::new(catalog, None, None, Cache::new(16, 4096));
+
+        let query = |serial: u32| {
+            let header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+            let question = DnsQuestion::new("example.com", DnsType::IXFR, DnsClass::IN);
+            let client_soa = version(serial, "").soa_record().clone();
+            DnsMessage::new(header, vec![question], vec![], vec![client_soa], vec![])
+        };
+        let serials = |message: &DnsMessage| -> Vec<Option<u32>> {
+            message
+                .answers
+                .iter()
+                .map(|answer| match &answer.data {
+                    RData::SOA(soa) => Some(soa.serial),
+                    _ => None,
+                })
+                .collect()
+        };
+        let client = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let responses = respond(&server, &query(1), client);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            serials(&responses[0]),
+            [
+                Some(3),
+                Some(1),
+                Some(2),
+                None,
+                Some(2),
+                None,
+                Some(3),
+                None,
+                Some(3)
+            ]
+        );
+        assert_eq!(
+            responses[0].answers[5].data,
+            RData::A(Ipv4Addr::new(192, 0, 2, 10))
+        );
+        assert_eq!(
+            serials(&respond(&server, &query(2), client)[0]),
+            [Some(3), Some(2), None, Some(3), None, Some(3)]
+        );
+
+        // up to date, or ahead of us, gets our SOA alone
+        for serial in [3, 4] {
+            assert_eq!(
+                serials(&respond(&server, &query(serial), client)[0]),
+                [Some(3)]
+            );
+        }
+
+        // older than the journal reaches gets the whole zone
+        assert_eq!(
+            serials(&respond(&server, &query(0), client)[0]),
+            [Some(3), None, None, Some(3)]
+        );
+
+        // over UDP the changes go in one datagram if they fit, or else just the SOA
+        assert_eq!(
+            respond_udp(&server, &query(1), client, 512).answers.len(),
+            9
+        );
+        assert_eq!(
+            respond_udp(&server, &query(1), client, 100).answers.len(),
+            1
+        );
+    }
+}
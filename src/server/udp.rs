@@ -0,0 +1,267 @@
+use std::{net::UdpSocket, sync::Arc};
+
+use crate::dns::{
+    header::DnsHeaderQR,
+    message::{DnsMessage, MAX_MESSAGE_SIZE},
+};
+
+use super::{pool::WorkerPool, transfer, Server, MAX_UDP_PAYLOAD_SIZE};
+
+/// Largest reply we send `query`: what its sender said it can receive, but no more than we
+/// advertise ourselves, as bigger datagrams only fragment (RFC 6891 section 6.2.5).
+fn response_size_limit(query: &DnsMessage) -> usize {
+    query
+        .max_udp_response_size()
+        .min(MAX_UDP_PAYLOAD_SIZE as usize)
+}
+
+/// Serves queries arriving on `udp_socket` until receiving fails. Up to `max_outstanding`
+/// queries are handled at once; past that we stop reading from the socket until one finishes,
+/// leaving further datagrams to queue in (and eventually overflow) the kernel buffer.
+pub fn serve(udp_socket: Arc<UdpSocket>, server: Arc<Server>, max_outstanding: usize) {
+    let pool = WorkerPool::new(max_outstanding);
+    let mut buf = vec![0; MAX_MESSAGE_SIZE];
+
+    loop {
+        match udp_socket.recv_from(&mut buf) {
+            Ok((size, source)) => {
+                let data = buf[..size].to_vec();
+                let udp_socket = udp_socket.clone();
+                let server = server.clone();
+
+                pool.execute(move || {
+                    // parse stuff
+                    let (mut response, max_size) = match DnsMessage::try_from(&data[..]) {
+                        // replies are dropped rather than answered, see `Server::handle_query`
+                        Ok(received_message)
+                            if received_message.header.query_response == DnsHeaderQR::Reply =>
+                        {
+                            return
+                        }
+                        Ok(received_message) if transfer::is_transfer(&received_message) => {
+                            let max_size = response_size_limit(&received_message);
+                            let response = transfer::respond_udp(
+                                &server,
+                                &received_message,
+                                source.ip(),
+                                max_size,
+                            );
+                            (response, max_size)
+                        }
+                        Ok(received_message) => {
+                            match server.handle_query(&received_message, source.ip()) {
+                                Some(response) => {
+                                    (response, response_size_limit(&received_message))
+                                }
+                                None => return,
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("Malformed query from {}: {}", source, e);
+                            match DnsMessage::new_format_error(&data) {
+                                Some(mut response) => {
+                                    response.header.recursion_available =
+                                        server.recursion_available();
+                                    (response, MAX_MESSAGE_SIZE)
+                                }
+                                None => return,
+                            }
+                        }
+                    };
+
+                    // never send more than the client said it can receive
+                    let response_buf = response.truncate_to(max_size);
+
+                    if let Err(e) = udp_socket.send_to(&response_buf, source) {
+                        eprintln!("Failed to send response: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                eprintln!("Error receiving data: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        path::Path,
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    use super::serve;
+    use crate::{
+        cache::Cache,
+        dns::{
+            answer::DnsAnswer,
+            common::{DnsClass, DnsName, DnsType},
+            edns::Edns,
+            header::{DnsHeader, DnsHeaderQR, DnsHeaderRA, DnsHeaderTC},
+            message::DnsMessage,
+            question::DnsQuestion,
+            rdata::RData,
+        },
+        forwarder::{
+            upstream::{Strategy, UpstreamSet},
+            Forwarder,
+        },
+        server::{Server, MAX_UDP_PAYLOAD_SIZE},
+        zone::{catalog::Catalog, parser::parse_str, Zone},
+    };
+
+    #[test]
+    fn test_udp_slow_upstream_does_not_block_other_queries() {
+        // upstream that takes its time over anything under slow.example.com
+        let upstream = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok((size, source)) = upstream.recv_from(&mut buf) {
+                let mut message = DnsMessage::try_from(&buf[..size]).unwrap();
+                let upstream = upstream.clone();
+                thread::spawn(move || {
+                    let name = message.questions[0].name.name.clone();
+                    if name.ends_with("slow.example.com") {
+                        thread::sleep(Duration::from_millis(500));
+                    }
+                    message.header.query_response = DnsHeaderQR::Reply;
+                    let rdata = RData::A(Ipv4Addr::new(192, 0, 2, 1));
+                    message.answers =
+                        vec![DnsAnswer::new(&name, DnsType::A, DnsClass::IN, 60, rdata)];
+                    upstream.send_to(&message.as_buf(), source).unwrap();
+                });
+            }
+        });
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let addr = socket.local_addr().unwrap();
+        let forwarder = Forwarder::new(
+            UpstreamSet::new(vec![upstream_addr], Strategy::Failover, Duration::ZERO),
+            Duration::from_secs(2),
+            1,
+        );
+        let server = Arc::new(Server::new(
+            Catalog::new(),
+            Some(forwarder),
+            None,
+            Cache::new(16, 4096),
+        ));
+        thread::spawn(move || serve(socket, server, 4));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        for (id, name) in [(1, "slow.example.com"), (2, "fast.example.com")] {
+            let mut header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+            header.id = id;
+            let question = DnsQuestion::new(name, DnsType::A, DnsClass::IN);
+            let query = DnsMessage::new(header, vec![question], vec![], vec![], vec![]);
+            client.send_to(&query.as_buf(), addr).unwrap();
+        }
+
+        // the fast answer overtakes the slow one
+        let mut buf = [0u8; 512];
+        let mut ids = vec![];
+        for _ in 0..2 {
+            let (size, _) = client.recv_from(&mut buf).unwrap();
+            ids.push(DnsMessage::try_from(&buf[..size]).unwrap().header.id);
+        }
+        assert_eq!(ids, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_udp_ignores_responses() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let addr = socket.local_addr().unwrap();
+        let server = Arc::new(Server::new(
+            Catalog::new(),
+            None,
+            None,
+            Cache::new(16, 4096),
+        ));
+        thread::spawn(move || serve(socket, server, 4));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let message = |id, query_response| {
+            let mut header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+            header.id = id;
+            header.query_response = query_response;
+            let question = DnsQuestion::new("example.com", DnsType::A, DnsClass::IN);
+            DnsMessage::new(header, vec![question], vec![], vec![], vec![]).as_buf()
+        };
+
+        // a well-formed response, then a malformed one, then a query
+        client
+            .send_to(&message(1, DnsHeaderQR::Reply), addr)
+            .unwrap();
+        let mut malformed = message(2, DnsHeaderQR::Reply);
+        malformed.truncate(14);
+        client.send_to(&malformed, addr).unwrap();
+        client
+            .send_to(&message(3, DnsHeaderQR::Question), addr)
+            .unwrap();
+
+        // only the query is answered
+        let mut buf = [0u8; 512];
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(DnsMessage::try_from(&buf[..size]).unwrap().header.id, 3);
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(client.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_udp_caps_advertised_payload_size() {
+        // an RRset far bigger than we'd ever send over UDP
+        let mut text = "$TTL 300\n@ SOA ns1 hostmaster 1 3600 600 86400 60\n  NS ns1\n".to_string();
+        for i in 0..200 {
+            text.push_str(&format!("big TXT \"{i} {}\"\n", "x".repeat(40)));
+        }
+        let origin = DnsName::new("example.com".into());
+        let zone = Zone::new(
+            origin.clone(),
+            parse_str(&text, &origin, Path::new(".")).unwrap(),
+        )
+        .unwrap();
+        let catalog = Catalog::new();
+        catalog.insert(zone);
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let addr = socket.local_addr().unwrap();
+        let server = Arc::new(Server::new(catalog, None, None, Cache::new(16, 4096)));
+        thread::spawn(move || serve(socket, server, 4));
+
+        // the client claims it can take the largest datagram there is
+        let header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        let question = DnsQuestion::new("big.example.com", DnsType::TXT, DnsClass::IN);
+        let mut query = DnsMessage::new(header, vec![question], vec![], vec![], vec![]);
+        query.edns = Some(Edns::new(65535));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        client.send_to(&query.as_buf(), addr).unwrap();
+        let mut buf = [0u8; 65535];
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        assert!(size <= MAX_UDP_PAYLOAD_SIZE as usize);
+        let response = DnsMessage::try_from(&buf[..size]).unwrap();
+        assert_eq!(response.header.truncation, DnsHeaderTC::Truncated);
+        // with nothing to forward to, we only answer for our own zones
+        assert_eq!(
+            response.header.recursion_available,
+            DnsHeaderRA::RecursionNotAvailable
+        );
+    }
+}
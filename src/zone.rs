@@ -0,0 +1,791 @@
+pub mod catalog;
+pub mod notify;
+pub mod parser;
+pub mod secondary;
+pub mod update;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::dns::{
+    additional::DnsAdditional,
+    answer::DnsAnswer,
+    authority::DnsAuthority,
+    common::{DnsClass, DnsName, DnsType},
+    header::{DnsHeader, DnsHeaderAA, DnsHeaderRcode},
+    message::DnsMessage,
+    question::DnsQuestion,
+    rdata::{DnsSoa, RData},
+};
+
+/// Outcome of looking a question up in a zone.
+#[derive(Debug, Clone)]
+pub enum ZoneLookup {
+    Answer(Vec<DnsAnswer>),
+    // the name is at or below a zone cut, so all we can do is point at the child's servers
+    Referral {
+        ns: Vec<DnsAuthority>,
+        glue: Vec<DnsAdditional>,
+    },
+    // the name is below a DNAME: the DNAME then the CNAME synthesized from it, which is missing
+    // if the substituted name is too long to exist (RFC 6672 section 2.2)
+    Dname(Vec<DnsAnswer>),
+    NoData,
+    NxDomain,
+}
+
+/// Whether `serial` comes after `than` in RFC 1982 serial number arithmetic, where serials wrap
+/// around and each one is newer than the 2^31 - 1 before it.
+pub fn serial_is_newer(serial: u32, than: u32) -> bool {
+    serial != than && serial.wrapping_sub(than) < 1 << 31
+}
+
+/// The changes taking a zone from one SOA serial to the next, as IXFR carries them (RFC 1995
+/// section 4).
+#[derive(Debug, Clone)]
+pub struct ZoneDiff {
+    pub old_soa: DnsAnswer,
+    pub deleted: Vec<DnsAnswer>,
+    pub new_soa: DnsAnswer,
+    pub added: Vec<DnsAnswer>,
+}
+
+/// How many aliases we follow within a zone before answering with the chain so far.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// How many past changes a zone remembers for IXFR; clients further behind get the whole zone.
+const MAX_JOURNAL_DIFFS: usize = 64;
+
+/// What clients may do with a zone besides querying it. Kept apart from the records so it carries
+/// over when a new copy of the zone replaces the old one.
+#[derive(Debug, Clone, Default)]
+pub struct ZonePolicy {
+    // clients allowed to transfer the whole zone; nobody by default
+    pub allow_transfer: Vec<IpAddr>,
+    // secondaries told with a NOTIFY whenever the zone changes
+    pub notify: Vec<SocketAddr>,
+    // clients allowed to change records with dynamic updates; nobody by default
+    pub allow_update: Vec<IpAddr>,
+}
+
+/// An authoritative zone held in memory. Every name between a record's owner and the apex has a
+/// node, so empty non-terminals exist (with no records) and answer NODATA rather than NXDOMAIN.
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub origin: DnsName,
+    pub class: DnsClass,
+    pub policy: ZonePolicy,
+    // set on a secondary copy gone unrefreshed past its expire interval, which answers SERVFAIL
+    // until a transfer replaces it
+    pub expired: bool,
+    nodes: HashMap<DnsName, Vec<DnsAnswer>>,
+    // the changes that led to this version of the zone, oldest first
+    journal: Vec<ZoneDiff>,
+}
+
+impl Zone {
+    /// Builds a zone from its records, which must all be at or below `origin` and include exactly
+    /// one SOA, at the apex. Names have to be valid, and a CNAME has to be alone at its name.
+    pub fn new(origin: DnsName, records: Vec<DnsAnswer>) -> anyhow::Result<Self> {
+        let soas: Vec<&DnsAnswer> = records
+            .iter()
+            .filter(|record| record.qtype == DnsType::SOA)
+            .collect();
+        let class = match soas[..] {
+            [soa] if soa.name == origin => soa.qclass,
+            [] => bail!("Zone {} has no SOA record", origin),
+            [_] => bail!("Zone {} has its SOA away from the apex", origin),
+            _ => bail!("Zone {} has more than one SOA record", origin),
+        };
+
+        let mut zone = Self {
+            origin,
+            class,
+            policy: ZonePolicy::default(),
+            expired: false,
+            nodes: HashMap::new(),
+            journal: vec![],
+        };
+        for record in records {
+            if !record.name.is_valid() {
+                bail!("{} is not a valid name", record.name);
+            }
+            if !record.name.is_subdomain_of(&zone.origin) {
+                bail!("{} is outside zone {}", record.name, zone.origin);
+            }
+            if record.qclass != zone.class {
+                bail!(
+                    "{} has class {} in a {} zone",
+                    record.name,
+                    record.qclass,
+                    zone.class
+                );
+            }
+            zone.add_record(record);
+        }
+
+        // a CNAME stands in for everything at its name (RFC 1034 section 3.6.2)
+        for (name, records) in &zone.nodes {
+            let cnames = records
+                .iter()
+                .filter(|record| record.qtype == DnsType::CNAME)
+                .count();
+            if cnames > 1 {
+                bail!("{} has more than one CNAME record", name);
+            }
+            if cnames == 1 && records.len() > 1 {
+                bail!("{} has a CNAME record beside other data", name);
+            }
+        }
+        Ok(zone)
+    }
+
+    /// Loads a zone from a master file. The apex is the owner of its SOA record; `origin` is
+    /// what relative names start out relative to.
+    pub fn load(path: &Path, origin: &DnsName) -> anyhow::Result<Self> {
+        let records = parser::parse_file(path, origin)?;
+        let apex = records
+            .iter()
+            .find(|record| record.qtype == DnsType::SOA)
+            .map(|soa| soa.name.clone())
+            .ok_or_else(|| anyhow!("{} has no SOA record", path.display()))?;
+        Self::new(apex, records)
+    }
+
+    fn add_record(&mut self, record: DnsAnswer) {
+        // make sure every ancestor up to the apex exists, if only as an empty non-terminal
+        let mut name = record.name.parent();
+        while let Some(ancestor) = name {
+            if !ancestor.is_subdomain_of(&self.origin) || self.nodes.contains_key(&ancestor) {
+                break;
+            }
+            name = ancestor.parent();
+            self.nodes.insert(ancestor, vec![]);
+        }
+
+        let records = self.nodes.entry(record.name.clone()).or_default();
+        // RRsets don't hold duplicates (RFC 2181 section 5)
+        if !records
+            .iter()
+            .any(|existing| existing.qtype == record.qtype && existing.data == record.data)
+        {
+            records.push(record);
+        }
+    }
+
+    pub fn soa_record(&self) -> &DnsAnswer {
+        self.rrset(&self.origin, DnsType::SOA)
+            .next()
+            .expect("a zone always has an SOA")
+    }
+
+    pub fn soa(&self) -> &DnsSoa {
+        match &self.soa_record().data {
+            RData::SOA(soa) => soa,
+            _ => unreachable!("SOA records always carry SOA RDATA"),
+        }
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.soa().serial
+    }
+
+    /// Records owned by `name`, or `None` if the name doesn't exist in the zone.
+    pub fn node(&self, name: &DnsName) -> Option<&[DnsAnswer]> {
+        self.nodes.get(name).map(Vec::as_slice)
+    }
+
+    pub fn rrset<'a>(
+        &'a self,
+        name: &DnsName,
+        qtype: DnsType,
+    ) -> impl Iterator<Item = &'a DnsAnswer> + 'a {
+        self.node(name)
+            .unwrap_or_default()
+            .iter()
+            .filter(move |record| record.qtype == qtype)
+    }
+
+    /// Every record in the zone, SOA first.
+    pub fn records(&self) -> impl Iterator<Item = &DnsAnswer> {
+        let soa = self.soa_record();
+        std::iter::once(soa).chain(
+            self.nodes
+                .values()
+                .flatten()
+                .filter(move |record| !std::ptr::eq(*record, soa)),
+        )
+    }
+
+    /// Builds the zone `diffs` lead to from this one, remembering them in its journal. The first
+    /// must start at our serial and each following one where the previous ended; deleting a record
+    /// we don't hold is an error, as it means our copy isn't what the diffs were made against.
+    pub fn apply(&self, diffs: &[ZoneDiff]) -> anyhow::Result<Zone> {
+        let mut soa = self.soa_record().clone();
+        let mut records: Vec<DnsAnswer> = self.records().skip(1).cloned().collect();
+        for diff in diffs {
+            let RData::SOA(current) = &soa.data else {
+                bail!("Difference for {} ends without an SOA", self.origin);
+            };
+            if !matches!(&diff.old_soa.data, RData::SOA(old) if old.serial == current.serial) {
+                bail!(
+                    "Difference for {} doesn't start from serial {}",
+                    self.origin,
+                    current.serial
+                );
+            }
+            for deleted in diff.deleted.iter().filter(|record| record.qtype != DnsType::SOA) {
+                let position = records
+                    .iter()
+                    .position(|record| {
+                        record.name == deleted.name
+                            && record.qtype == deleted.qtype
+                            && record.qclass == deleted.qclass
+                            && record.data == deleted.data
+                    })
+                    .ok_or_else(|| anyhow!("{} isn't in zone {}", deleted, self.origin))?;
+                records.swap_remove(position);
+            }
+            records.extend(
+                diff.added
+                    .iter()
+                    .filter(|record| record.qtype != DnsType::SOA)
+                    .cloned(),
+            );
+            soa = diff.new_soa.clone();
+        }
+
+        records.push(soa);
+        let mut zone = Zone::new(self.origin.clone(), records)?;
+        zone.policy = self.policy.clone();
+        zone.journal = self.journal.clone();
+        zone.record_changes(diffs.iter().cloned());
+        Ok(zone)
+    }
+
+    /// Takes over the journal of `previous`, the version this zone replaces, adding the
+    /// difference between the two. Nothing is remembered if this version isn't newer.
+    pub fn with_history(mut self, previous: &Zone) -> Zone {
+        if !serial_is_newer(self.serial(), previous.serial()) {
+            return self;
+        }
+
+        // a record whose TTL changed counts as deleted and added again
+        let key = |record: &DnsAnswer| {
+            (
+                record.name.clone(),
+                record.qtype,
+                record.qclass,
+                record.ttl,
+                record.data.as_buf(),
+            )
+        };
+        let old: HashSet<_> = previous.records().skip(1).map(key).collect();
+        let new: HashSet<_> = self.records().skip(1).map(key).collect();
+        let diff = ZoneDiff {
+            old_soa: previous.soa_record().clone(),
+            deleted: previous
+                .records()
+                .skip(1)
+                .filter(|record| !new.contains(&key(record)))
+                .cloned()
+                .collect(),
+            new_soa: self.soa_record().clone(),
+            added: self
+                .records()
+                .skip(1)
+                .filter(|record| !old.contains(&key(record)))
+                .cloned()
+                .collect(),
+        };
+
+        self.journal = previous.journal.clone();
+        self.record_changes([diff]);
+        self
+    }
+
+    fn record_changes(&mut self, diffs: impl IntoIterator<Item = ZoneDiff>) {
+        self.journal.extend(diffs);
+        let excess = self.journal.len().saturating_sub(MAX_JOURNAL_DIFFS);
+        self.journal.drain(..excess);
+    }
+
+    /// The journaled changes from `serial` up to this version, or `None` if the journal doesn't
+    /// reach back that far.
+    pub fn changes_since(&self, serial: u32) -> Option<&[ZoneDiff]> {
+        let start = self.journal.iter().position(|diff| {
+            matches!(&diff.old_soa.data, RData::SOA(old) if old.serial == serial)
+        })?;
+        Some(&self.journal[start..])
+    }
+
+    /// Writes the zone out as a master file. The file is replaced in one step, so a crash midway
+    /// leaves the previous copy rather than half of this one.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut text = String::new();
+        for record in self.records() {
+            writeln!(text, "{}", record).expect("writing to a String can't fail");
+        }
+
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, text).with_context(|| format!("Failed to write {}", temp.display()))?;
+        fs::rename(&temp, path).with_context(|| format!("Failed to replace {}", path.display()))
+    }
+
+    /// The highest point between the apex and `name` where the zone stops answering for it
+    /// directly: a zone cut below the apex, or a DNAME owned by a proper ancestor of `name`.
+    fn find_redirection(&self, name: &DnsName) -> Option<ZoneLookup> {
+        let depth = self.origin.label_count();
+        let labels: Vec<&str> = name.labels().collect();
+        (depth..=labels.len()).find_map(|count| {
+            let ancestor = DnsName::new(labels[labels.len() - count..].join("."));
+            let ns: Vec<DnsAuthority> = self.rrset(&ancestor, DnsType::NS).cloned().collect();
+            if count > depth && !ns.is_empty() {
+                let glue = self.address_records(&ns);
+                return Some(ZoneLookup::Referral { ns, glue });
+            }
+
+            // a DNAME redirects the names below its owner, not the owner itself
+            if count == labels.len() {
+                return None;
+            }
+            let dname = self.rrset(&ancestor, DnsType::DNAME).next()?;
+            let RData::DNAME(target) = &dname.data else {
+                unreachable!("DNAME records always carry DNAME RDATA");
+            };
+            let cname = name
+                .replace_suffix(&ancestor, target)
+                .map(|target| DnsAnswer {
+                    name: name.clone(),
+                    qtype: DnsType::CNAME,
+                    qclass: dname.qclass,
+                    ttl: dname.ttl,
+                    data: RData::CNAME(target),
+                });
+            Some(ZoneLookup::Dname(
+                std::iter::once(dname.clone()).chain(cname).collect(),
+            ))
+        })
+    }
+
+    /// Records of the wildcard covering `name`, a name that doesn't exist: the `*` child of its
+    /// closest encloser, which is the nearest ancestor that does (RFC 4592 section 3.3.1). Empty
+    /// non-terminals are nodes too, so they stop wildcards further up from applying.
+    fn wildcard(&self, name: &DnsName) -> Option<&[DnsAnswer]> {
+        let mut encloser = name.parent()?;
+        while !self.nodes.contains_key(&encloser) {
+            encloser = encloser.parent()?;
+        }
+        let labels: Vec<&str> = std::iter::once("*").chain(encloser.labels()).collect();
+        self.node(&DnsName::new(labels.join(".")))
+    }
+
+    /// Looks `name` up one step, without following aliases.
+    pub fn lookup(&self, name: &DnsName, qtype: DnsType) -> ZoneLookup {
+        if let Some(redirection) = self.find_redirection(name) {
+            return redirection;
+        }
+
+        // a name that doesn't exist may still be covered by a wildcard, whose records are answered
+        // as if the name owned them
+        let (records, synthesized) = match self.node(name) {
+            Some(records) => (records, false),
+            None => match self.wildcard(name) {
+                Some(records) => (records, true),
+                None => return ZoneLookup::NxDomain,
+            },
+        };
+        // ANY gets every RRset at the name
+        let rrset = |qtype: DnsType| -> Vec<DnsAnswer> {
+            records
+                .iter()
+                .filter(|record| qtype == DnsType::ANY || record.qtype == qtype)
+                .map(|record| match synthesized {
+                    true => DnsAnswer {
+                        name: name.clone(),
+                        ..record.clone()
+                    },
+                    false => record.clone(),
+                })
+                .collect()
+        };
+
+        let matching = rrset(qtype);
+        if !matching.is_empty() {
+            return ZoneLookup::Answer(matching);
+        }
+
+        // an alias answers for every type at its name
+        let cname = rrset(DnsType::CNAME);
+        match cname.is_empty() {
+            true => ZoneLookup::NoData,
+            false => ZoneLookup::Answer(cname),
+        }
+    }
+
+    /// A and AAAA records this zone holds for names the given records point at, for the
+    /// additional section.
+    fn address_records(&self, records: &[DnsAnswer]) -> Vec<DnsAdditional> {
+        records
+            .iter()
+            .filter_map(|record| match &record.data {
+                RData::NS(target) => Some(target),
+                RData::MX { exchange, .. } => Some(exchange),
+                RData::SRV { target, .. } => Some(target),
+                _ => None,
+            })
+            .flat_map(|target| {
+                self.rrset(target, DnsType::A)
+                    .chain(self.rrset(target, DnsType::AAAA))
+                    .cloned()
+            })
+            .collect()
+    }
+
+    /// The SOA as it goes in the authority section of a negative answer, with the negative TTL
+    /// (RFC 2308 section 3).
+    fn negative_soa(&self) -> DnsAuthority {
+        let soa = self.soa_record();
+        DnsAuthority {
+            ttl: soa.ttl.min(self.soa().minimum),
+            ..soa.clone()
+        }
+    }
+
+    /// Builds the authoritative response to `question`. ID and RD are left for the caller.
+    ///
+    /// CNAMEs and DNAMEs are followed while their targets stay in this zone, with each alias in
+    /// the answer section ahead of what it points at. The response code and authority section
+    /// describe the last name in the chain (RFC 6604).
+    pub fn answer(&self, question: &DnsQuestion) -> DnsMessage {
+        let header = DnsHeader::try_from(&[0u8; 12][..]).expect("a zeroed header is valid");
+        let mut response = DnsMessage::new_error_response(&header, DnsHeaderRcode::NoError);
+        response.header.authoritative_answer = DnsHeaderAA::Authoritative;
+        response.questions = vec![question.clone()];
+
+        let mut name = question.name.clone();
+        let mut chain = vec![];
+        loop {
+            let target = match self.lookup(&name, question.qtype) {
+                ZoneLookup::Answer(answers) => {
+                    let target = match &answers[0].data {
+                        RData::CNAME(target)
+                            if !matches!(question.qtype, DnsType::CNAME | DnsType::ANY) =>
+                        {
+                            Some(target.clone())
+                        }
+                        _ => None,
+                    };
+                    response.answers.extend(answers);
+                    target
+                }
+                ZoneLookup::Dname(records) => {
+                    let target = match records.get(1).map(|cname| &cname.data) {
+                        Some(RData::CNAME(target)) => Some(target.clone()),
+                        _ => {
+                            response.header.rcode = DnsHeaderRcode::YXDomain;
+                            None
+                        }
+                    };
+                    response.answers.extend(records);
+                    target
+                }
+                ZoneLookup::Referral { ns, glue } => {
+                    // only aliases we answered for on the way here are authoritative
+                    if response.answers.is_empty() {
+                        response.header.authoritative_answer = DnsHeaderAA::NonAuthoritative;
+                    }
+                    response.authorities = ns;
+                    response.additional = glue;
+                    None
+                }
+                ZoneLookup::NoData => {
+                    response.authorities = vec![self.negative_soa()];
+                    None
+                }
+                ZoneLookup::NxDomain => {
+                    response.header.rcode = DnsHeaderRcode::NameError;
+                    response.authorities = vec![self.negative_soa()];
+                    None
+                }
+            };
+
+            // targets elsewhere are left to the client, and loops end once they come round again
+            chain.push(name);
+            match target {
+                Some(target)
+                    if target.is_subdomain_of(&self.origin)
+                        && !chain.contains(&target)
+                        && chain.len() < MAX_CNAME_CHAIN =>
+                {
+                    name = target
+                }
+                _ => break,
+            }
+        }
+
+        let addresses = self.address_records(&response.answers);
+        response.additional.extend(addresses);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, path::Path};
+
+    use super::{parser::parse_str, Zone};
+    use crate::dns::{
+        answer::DnsAnswer,
+        common::{DnsClass, DnsName, DnsType},
+        header::{DnsHeaderAA, DnsHeaderRcode},
+        message::DnsMessage,
+        question::DnsQuestion,
+        rdata::RData,
+    };
+
+    fn example_zone() -> Zone {
+        let text = "
+$TTL 3600
+@           SOA     ns1 hostmaster 1 7200 3600 1209600 300
+            NS      ns1
+ns1         A       192.0.2.1
+www         A       192.0.2.10
+            MX      10 mail
+mail        A       192.0.2.25
+alias       CNAME   www
+a.b.c       TXT     \"deep\"
+sub         NS      ns.sub
+ns.sub      A       192.0.2.53
+chain       CNAME   alias
+loop1       CNAME   loop2
+loop2       CNAME   loop1
+away        CNAME   www.example.net.
+old         DNAME   new
+host.new    A       192.0.2.80
+*.preview   A       192.0.2.99
+x.y.preview A       192.0.2.98
+*.cdn       CNAME   www
+";
+        let origin = DnsName::new("example.com".into());
+        let records = parse_str(text, &origin, Path::new(".")).unwrap();
+        Zone::new(origin, records).unwrap()
+    }
+
+    #[test]
+    fn test_zone_answers_authoritatively() {
+        let zone = example_zone();
+        let ask = |name: &str, qtype| zone.answer(&DnsQuestion::new(name, qtype, DnsClass::IN));
+
+        let response = ask("WWW.example.com", DnsType::A);
+        assert_eq!(
+            response.header.authoritative_answer,
+            DnsHeaderAA::Authoritative
+        );
+        assert_eq!(
+            response.answers[0].data,
+            RData::A(Ipv4Addr::new(192, 0, 2, 10))
+        );
+
+        // MX targets come with their addresses
+        let response = ask("www.example.com", DnsType::MX);
+        assert_eq!(
+            response.additional[0].name,
+            DnsName::new("mail.example.com".into())
+        );
+
+        let response = ask("alias.example.com", DnsType::A);
+        assert_eq!(response.answers[0].qtype, DnsType::CNAME);
+
+        // ANY gets every RRset at the name, and an alias without following it
+        let types = |name| -> Vec<DnsType> {
+            let response = ask(name, DnsType::ANY);
+            response.answers.iter().map(|answer| answer.qtype).collect()
+        };
+        assert_eq!(types("www.example.com"), [DnsType::A, DnsType::MX]);
+        assert_eq!(types("alias.example.com"), [DnsType::CNAME]);
+
+        // NODATA, including for the empty non-terminal b.c
+        for name in ["www.example.com", "b.c.example.com"] {
+            let response = ask(name, DnsType::AAAA);
+            assert_eq!(response.header.rcode, DnsHeaderRcode::NoError);
+            assert!(response.answers.is_empty());
+            assert_eq!(response.authorities[0].qtype, DnsType::SOA);
+            assert_eq!(response.authorities[0].ttl, 300);
+        }
+
+        let response = ask("nope.example.com", DnsType::A);
+        assert_eq!(response.header.rcode, DnsHeaderRcode::NameError);
+        assert_eq!(response.authorities[0].qtype, DnsType::SOA);
+
+        // anything at or below the cut is referred with glue
+        let response = ask("host.sub.example.com", DnsType::A);
+        assert_eq!(
+            response.header.authoritative_answer,
+            DnsHeaderAA::NonAuthoritative
+        );
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities[0].qtype, DnsType::NS);
+        assert_eq!(
+            response.additional[0].data,
+            RData::A(Ipv4Addr::new(192, 0, 2, 53))
+        );
+
+        assert!(Zone::new(
+            DnsName::new("example.net".into()),
+            zone.records().cloned().collect()
+        )
+        .is_err());
+
+        // neither a CNAME beside other data nor a label too long to encode makes it in
+        let alias = DnsAnswer::new(
+            "www.example.com",
+            DnsType::CNAME,
+            DnsClass::IN,
+            300,
+            RData::CNAME(DnsName::new("example.com".into())),
+        );
+        let long = format!("{}.example.com", "x".repeat(64));
+        let long = DnsAnswer::new(
+            &long,
+            DnsType::A,
+            DnsClass::IN,
+            300,
+            RData::A([192, 0, 2, 1].into()),
+        );
+        for bad in [alias, long] {
+            let records = zone.records().cloned().chain([bad]).collect();
+            assert!(Zone::new(zone.origin.clone(), records).is_err());
+        }
+    }
+
+    #[test]
+    fn test_zone_follows_aliases() {
+        let zone = example_zone();
+        let ask = |name: &str| zone.answer(&DnsQuestion::new(name, DnsType::A, DnsClass::IN));
+        let chain = |response: &DnsMessage| -> Vec<(String, DnsType)> {
+            response
+                .answers
+                .iter()
+                .map(|answer| (answer.name.to_string(), answer.qtype))
+                .collect()
+        };
+
+        let response = ask("chain.example.com");
+        assert_eq!(
+            chain(&response),
+            [
+                ("chain.example.com.".to_string(), DnsType::CNAME),
+                ("alias.example.com.".to_string(), DnsType::CNAME),
+                ("www.example.com.".to_string(), DnsType::A),
+            ]
+        );
+
+        // a loop stops once it comes back round, and out-of-zone targets are left to the client
+        assert_eq!(ask("loop1.example.com").answers.len(), 2);
+        let response = ask("away.example.com");
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.header.rcode, DnsHeaderRcode::NoError);
+
+        // the DNAME comes first, then the CNAME synthesized from it, then the target's data
+        let response = ask("host.old.example.com");
+        assert_eq!(
+            chain(&response),
+            [
+                ("old.example.com.".to_string(), DnsType::DNAME),
+                ("host.old.example.com.".to_string(), DnsType::CNAME),
+                ("host.new.example.com.".to_string(), DnsType::A),
+            ]
+        );
+        assert_eq!(
+            response.answers[1].data,
+            RData::CNAME(DnsName::new("host.new.example.com".into()))
+        );
+
+        // the rcode is the one for the end of the chain
+        let response = ask("gone.old.example.com");
+        assert_eq!(response.header.rcode, DnsHeaderRcode::NameError);
+        assert_eq!(response.answers.len(), 2);
+
+        // the DNAME owner itself isn't redirected
+        let response = ask("old.example.com");
+        assert!(response.answers.is_empty());
+
+        // substituting into a name too long to exist is YXDOMAIN
+        let label = "a".repeat(63);
+        let origin = DnsName::new("example.com".into());
+        let text = format!(
+            "$TTL 60\n@ SOA ns1 hostmaster 1 2 3 4 5\nlong DNAME {label}.{label}.{label}\n"
+        );
+        let zone = Zone::new(
+            origin.clone(),
+            parse_str(&text, &origin, Path::new(".")).unwrap(),
+        )
+        .unwrap();
+        let response = zone.answer(&DnsQuestion::new(
+            &format!("{label}.long.example.com"),
+            DnsType::A,
+            DnsClass::IN,
+        ));
+        assert_eq!(response.header.rcode, DnsHeaderRcode::YXDomain);
+        assert_eq!(response.answers[0].qtype, DnsType::DNAME);
+    }
+
+    #[test]
+    fn test_zone_synthesizes_from_wildcards() {
+        let zone = example_zone();
+        let ask = |name: &str, qtype| zone.answer(&DnsQuestion::new(name, qtype, DnsClass::IN));
+
+        // the owner is rewritten to the name asked about, however deep below the wildcard it is
+        for name in ["pr-42.preview.example.com", "a.b.preview.example.com"] {
+            let response = ask(name, DnsType::A);
+            assert_eq!(response.header.rcode, DnsHeaderRcode::NoError);
+            assert_eq!(response.answers[0].name, DnsName::new(name.into()));
+            assert_eq!(
+                response.answers[0].data,
+                RData::A(Ipv4Addr::new(192, 0, 2, 99))
+            );
+        }
+        let response = ask("pr-42.preview.example.com", DnsType::AAAA);
+        assert_eq!(response.header.rcode, DnsHeaderRcode::NoError);
+        assert!(response.answers.is_empty());
+
+        // names that exist, empty non-terminals included, aren't covered by the wildcard
+        let response = ask("y.preview.example.com", DnsType::A);
+        assert_eq!(response.header.rcode, DnsHeaderRcode::NoError);
+        assert!(response.answers.is_empty());
+        let response = ask("z.y.preview.example.com", DnsType::A);
+        assert_eq!(response.header.rcode, DnsHeaderRcode::NameError);
+        let response = ask("x.y.preview.example.com", DnsType::A);
+        assert_eq!(
+            response.answers[0].data,
+            RData::A(Ipv4Addr::new(192, 0, 2, 98))
+        );
+
+        // asking for the wildcard itself gets it as it is
+        let response = ask("*.preview.example.com", DnsType::A);
+        assert_eq!(
+            response.answers[0].name,
+            DnsName::new("*.preview.example.com".into())
+        );
+
+        // a wildcard CNAME is synthesized and then followed
+        let response = ask("img.cdn.example.com", DnsType::A);
+        assert_eq!(
+            response.answers[0].name,
+            DnsName::new("img.cdn.example.com".into())
+        );
+        assert_eq!(response.answers[0].qtype, DnsType::CNAME);
+        assert_eq!(
+            response.answers[1].data,
+            RData::A(Ipv4Addr::new(192, 0, 2, 10))
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::dns::common::DnsName;
+
+use super::Zone;
+
+/// The zones we serve, keyed by apex. Zones are swapped in whole, so readers holding an `Arc`
+/// keep a consistent copy while a newer one is installed.
+#[derive(Debug, Default)]
+pub struct Catalog {
+    zones: RwLock<HashMap<DnsName, Arc<Zone>>>,
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.zones.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds `zone`, replacing any zone with the same apex.
+    pub fn insert(&self, zone: Zone) {
+        let origin = zone.origin.clone();
+        self.zones.write().unwrap().insert(origin, Arc::new(zone));
+    }
+
+    pub fn get(&self, origin: &DnsName) -> Option<Arc<Zone>> {
+        self.zones.read().unwrap().get(origin).cloned()
+    }
+
+    /// The most specific zone containing `name`.
+    pub fn find(&self, name: &DnsName) -> Option<Arc<Zone>> {
+        let zones = self.zones.read().unwrap();
+        let mut candidate = Some(name.clone());
+        while let Some(name) = candidate {
+            if let Some(zone) = zones.get(&name) {
+                return Some(zone.clone());
+            }
+            candidate = name.parent();
+        }
+        None
+    }
+}
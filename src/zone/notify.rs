@@ -0,0 +1,139 @@
+use std::{net::SocketAddr, thread, time::Duration};
+
+use anyhow::bail;
+
+use crate::{
+    dns::{
+        common::{DnsClass, DnsType},
+        header::{DnsHeaderAA, DnsHeaderOpcode, DnsHeaderRD, DnsHeaderRcode},
+        message::DnsMessage,
+        question::DnsQuestion,
+    },
+    forwarder::{new_request, query_upstream},
+};
+
+use super::Zone;
+
+/// How long to wait for the first acknowledgement; each retry waits twice as long as the last.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// NOTIFYs sent to a secondary before giving up on it (RFC 1996 section 3.6).
+const MAX_NOTIFY_ATTEMPTS: u32 = 5;
+
+/// Tells every secondary in the zone's policy that it changed, each from its own thread so a slow
+/// or unreachable one holds up nobody.
+pub fn notify_secondaries(zone: &Zone) {
+    for target in zone.policy.notify.iter().copied() {
+        let zone_name = zone.origin.clone();
+        let request = notify_request(zone);
+        thread::spawn(move || {
+            if let Err(e) = send_notify(target, &request) {
+                eprintln!("Failed to notify {} about {}: {:#}", target, zone_name, e);
+            }
+        });
+    }
+}
+
+/// The NOTIFY for a zone, carrying its current SOA so the secondary may skip checking when it
+/// already has that serial (RFC 1996 section 3.7).
+pub fn notify_request(zone: &Zone) -> DnsMessage {
+    let question = DnsQuestion::new(&zone.origin.name, DnsType::SOA, DnsClass::IN);
+    let mut request = new_request(&question, DnsHeaderRD::RecursionNotDesired, false);
+    request.header.opcode = DnsHeaderOpcode::Notify;
+    request.header.authoritative_answer = DnsHeaderAA::Authoritative;
+    request.answers = vec![zone.soa_record().clone()];
+    request.edns = None;
+    request
+}
+
+/// Sends a NOTIFY to `target` until it acknowledges it, backing off between attempts.
+pub fn send_notify(target: SocketAddr, request: &DnsMessage) -> anyhow::Result<()> {
+    let mut timeout = NOTIFY_TIMEOUT;
+    let mut attempt = 1;
+    loop {
+        match query_upstream(target, request, timeout) {
+            Ok(reply) if reply.header.rcode == DnsHeaderRcode::NoError => return Ok(()),
+            Ok(reply) => bail!("NOTIFY answered {:?}", reply.header.rcode),
+            Err(e) if attempt >= MAX_NOTIFY_ATTEMPTS => return Err(e),
+            Err(_) => {}
+        }
+        attempt += 1;
+        timeout *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, UdpSocket},
+        path::Path,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{notify_request, send_notify};
+    use crate::{
+        cache::Cache,
+        dns::{
+            common::DnsName,
+            header::{DnsHeaderOpcode, DnsHeaderQR, DnsHeaderRcode},
+            message::DnsMessage,
+        },
+        server::{udp, Server},
+        zone::{catalog::Catalog, parser::parse_str, secondary::Secondary, Zone},
+    };
+
+    #[test]
+    fn test_notify_reaches_secondary() {
+        let origin = DnsName::new("example.com".into());
+        let text = "$TTL 300\n@ SOA ns1 hostmaster 1 3600 600 86400 60\n  NS ns1\n";
+        let zone = Zone::new(
+            origin.clone(),
+            parse_str(text, &origin, Path::new(".")).unwrap(),
+        )
+        .unwrap();
+        let request = notify_request(&zone);
+
+        // a secondary that misses the first NOTIFY is sent another
+        let flaky = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let flaky_addr = flaky.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            flaky.recv_from(&mut buf).unwrap();
+            let (size, source) = flaky.recv_from(&mut buf).unwrap();
+            let mut reply = DnsMessage::try_from(&buf[..size]).unwrap();
+            reply.header.query_response = DnsHeaderQR::Reply;
+            flaky.send_to(&reply.as_buf(), source).unwrap();
+        });
+        send_notify(flaky_addr, &request).unwrap();
+
+        // our own server, as a secondary, accepts it from the primary and checks the zone early
+        let secondary = Secondary::new(
+            origin.clone(),
+            vec!["127.0.0.1:53".parse().unwrap()],
+            None,
+            Duration::from_secs(1),
+        );
+        let mut server = Server::new(Catalog::new(), None, None, Cache::new(16, 4096));
+        server.secondaries.insert(origin, secondary.handle());
+        let server = Arc::new(server);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let udp_server = server.clone();
+        thread::spawn(move || udp::serve(socket.into(), udp_server, 4));
+
+        send_notify(addr, &request).unwrap();
+        let start = Instant::now();
+        assert!(secondary.wait(Duration::from_secs(10)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // anyone but the primary is refused
+        let response = server
+            .handle_query(&request, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))
+            .unwrap();
+        assert_eq!(response.header.opcode, DnsHeaderOpcode::Notify);
+        assert_eq!(response.header.rcode, DnsHeaderRcode::Refused);
+        assert!(!secondary.wait(Duration::ZERO));
+    }
+}
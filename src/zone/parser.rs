@@ -0,0 +1,320 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::dns::{
+    answer::DnsAnswer,
+    common::{parse_ttl, DnsClass, DnsName, DnsType},
+    rdata::RData,
+};
+
+/// How deeply `$INCLUDE`d files may nest, so a file including itself fails instead of looping.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// One record or directive, possibly spread over several lines with parentheses.
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    // the entry started with whitespace, so it reuses the previous owner
+    inherits_owner: bool,
+    fields: Vec<String>,
+}
+
+/// Splits master file text into entries, dropping comments, quotes and parentheses. Escapes are
+/// left in place for the field parsers to decode.
+fn tokenize(text: &str) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    let mut fields = vec![];
+    let mut field: Option<String> = None;
+    let mut line = 1;
+    let mut entry_line = 1;
+    let mut inherits_owner = false;
+    let mut depth = 0;
+    let mut at_line_start = true;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if at_line_start && depth == 0 && fields.is_empty() && field.is_none() {
+            entry_line = line;
+            inherits_owner = c == ' ' || c == '\t';
+        }
+        at_line_start = false;
+
+        match c {
+            '\\' => {
+                let escaped = chars
+                    .next()
+                    .ok_or_else(|| anyhow!("line {line}: dangling escape"))?;
+                let field = field.get_or_insert_with(String::new);
+                field.push('\\');
+                field.push(escaped);
+            }
+            '"' => {
+                let field = field.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            field.push('\\');
+                            field.extend(chars.next());
+                        }
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            field.push(c);
+                        }
+                        None => bail!("line {line}: unterminated quoted string"),
+                    }
+                }
+            }
+            ';' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '(' | ')' | ' ' | '\t' | '\r' | '\n' => {
+                fields.extend(field.take());
+                match c {
+                    '(' => depth += 1,
+                    ')' if depth == 0 => bail!("line {line}: unbalanced parentheses"),
+                    ')' => depth -= 1,
+                    '\n' => {
+                        line += 1;
+                        at_line_start = true;
+                        if depth == 0 && !fields.is_empty() {
+                            entries.push(Entry {
+                                line: entry_line,
+                                inherits_owner,
+                                fields: std::mem::take(&mut fields),
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => field.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if depth > 0 {
+        bail!("line {line}: unbalanced parentheses");
+    }
+    fields.extend(field);
+    if !fields.is_empty() {
+        entries.push(Entry {
+            line: entry_line,
+            inherits_owner,
+            fields,
+        });
+    }
+    Ok(entries)
+}
+
+/// Reads RFC 1035 master files, tracking the `$ORIGIN`, `$TTL` and previous owner state that
+/// records inherit.
+#[derive(Debug)]
+struct Parser {
+    origin: DnsName,
+    default_ttl: Option<u32>,
+    last_owner: Option<DnsName>,
+    last_ttl: Option<u32>,
+    last_class: DnsClass,
+    records: Vec<DnsAnswer>,
+}
+
+impl Parser {
+    fn parse(&mut self, text: &str, dir: &Path, depth: usize) -> anyhow::Result<()> {
+        for entry in tokenize(text)? {
+            self.parse_entry(&entry, dir, depth)
+                .with_context(|| format!("line {}", entry.line))?;
+        }
+        Ok(())
+    }
+
+    fn parse_entry(&mut self, entry: &Entry, dir: &Path, depth: usize) -> anyhow::Result<()> {
+        let fields: Vec<&str> = entry.fields.iter().map(String::as_str).collect();
+
+        match fields[0].to_ascii_uppercase().as_str() {
+            "$ORIGIN" if !entry.inherits_owner => {
+                let [_, origin] = fields[..] else {
+                    bail!("$ORIGIN takes a single name");
+                };
+                self.origin = DnsName::from_text(origin, &self.origin)?;
+            }
+            "$TTL" if !entry.inherits_owner => {
+                let [_, ttl] = fields[..] else {
+                    bail!("$TTL takes a single value");
+                };
+                self.default_ttl = Some(parse_ttl(ttl)?);
+            }
+            "$INCLUDE" if !entry.inherits_owner => {
+                let (file, origin) = match fields[..] {
+                    [_, file] => (file, self.origin.clone()),
+                    [_, file, origin] => (file, DnsName::from_text(origin, &self.origin)?),
+                    _ => bail!("$INCLUDE takes a file name and an optional origin"),
+                };
+                if depth >= MAX_INCLUDE_DEPTH {
+                    bail!("$INCLUDE nested too deeply");
+                }
+
+                // the included file gets its own origin, which doesn't leak back out
+                let saved_origin = std::mem::replace(&mut self.origin, origin);
+                let path = dir.join(file);
+                let text = fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                let include_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+                self.parse(&text, &include_dir, depth + 1)
+                    .with_context(|| path.display().to_string())?;
+                self.origin = saved_origin;
+            }
+            directive if directive.starts_with('$') && !entry.inherits_owner => {
+                bail!("unknown directive {}", fields[0]);
+            }
+            _ => self.parse_record(&fields, entry.inherits_owner)?,
+        }
+        Ok(())
+    }
+
+    /// `[owner] [ttl] [class] type rdata`, where TTL and class may come in either order.
+    fn parse_record(&mut self, fields: &[&str], inherits_owner: bool) -> anyhow::Result<()> {
+        let mut fields = fields.iter().copied();
+        let owner = match inherits_owner {
+            true => self
+                .last_owner
+                .clone()
+                .ok_or_else(|| anyhow!("record has no owner"))?,
+            false => DnsName::from_text(fields.next().unwrap_or_default(), &self.origin)?,
+        };
+
+        let mut ttl = None;
+        let mut class = None;
+        let qtype = loop {
+            let field = fields.next().ok_or_else(|| anyhow!("record has no type"))?;
+            if ttl.is_none() && field.starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(parse_ttl(field)?);
+            } else if let (None, Ok(parsed)) = (class, field.parse::<DnsClass>()) {
+                class = Some(parsed);
+            } else {
+                break field.parse::<DnsType>()?;
+            }
+        };
+
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or_else(|| anyhow!("record has no TTL and no $TTL is set"))?;
+        let class = class.unwrap_or(self.last_class);
+        let rdata: Vec<&str> = fields.collect();
+        let data = RData::from_text(qtype, &rdata, &self.origin)?;
+
+        self.last_owner = Some(owner.clone());
+        self.last_ttl = Some(ttl);
+        self.last_class = class;
+        self.records.push(DnsAnswer {
+            name: owner,
+            qtype,
+            qclass: class,
+            ttl,
+            data,
+        });
+        Ok(())
+    }
+}
+
+/// Parses master file text. Names are relative to `origin` until a `$ORIGIN` says otherwise, and
+/// `$INCLUDE` paths are resolved against `dir`.
+pub fn parse_str(text: &str, origin: &DnsName, dir: &Path) -> anyhow::Result<Vec<DnsAnswer>> {
+    let mut parser = Parser {
+        origin: origin.clone(),
+        default_ttl: None,
+        last_owner: None,
+        last_ttl: None,
+        last_class: DnsClass::IN,
+        records: vec![],
+    };
+    parser.parse(text, dir, 0)?;
+    Ok(parser.records)
+}
+
+/// Parses the master file at `path`.
+pub fn parse_file(path: &Path, origin: &DnsName) -> anyhow::Result<Vec<DnsAnswer>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    parse_str(&text, origin, &dir).with_context(|| path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, net::Ipv4Addr, path::Path};
+
+    use super::parse_str;
+    use crate::dns::{
+        common::{DnsClass, DnsName, DnsType},
+        rdata::RData,
+    };
+
+    #[test]
+    fn test_parse_master_file() {
+        let dir = env::temp_dir().join(format!("zone-parser-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hosts.inc"), "mail A 192.0.2.25\n").unwrap();
+
+        let text = r#"
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2024010101 ; serial
+            7200 3600 2w 300 )
+    IN  NS  ns1
+ns1     A   192.0.2.1
+www 300 IN CNAME @
+txt IN 60 TXT "hello \"world\"" two\032words
+$ORIGIN sub.example.com.
+host    AAAA 2001:db8::1
+$INCLUDE hosts.inc example.org.
+after   A   192.0.2.2
+"#;
+        let origin = DnsName::new("example.com".into());
+        let records = parse_str(text, &origin, &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let RData::SOA(soa) = &records[0].data else {
+            panic!("expected an SOA first");
+        };
+        assert_eq!(records[0].name, origin);
+        assert_eq!(records[0].ttl, 3600);
+        assert_eq!(soa.mname, DnsName::new("ns1.example.com".into()));
+        assert_eq!(soa.serial, 2024010101);
+        assert_eq!(soa.expire, 1209600);
+
+        // a leading blank reuses the previous owner
+        assert_eq!(records[1].name, origin);
+        assert_eq!(records[1].qtype, DnsType::NS);
+        assert_eq!(
+            records[3].data,
+            RData::CNAME(DnsName::new("example.com".into()))
+        );
+        assert_eq!(records[3].ttl, 300);
+        assert_eq!(records[4].qclass, DnsClass::IN);
+        assert_eq!(
+            records[4].data,
+            RData::TXT(vec![b"hello \"world\"".to_vec(), b"two words".to_vec()])
+        );
+        assert_eq!(records[5].name, DnsName::new("host.sub.example.com".into()));
+
+        // included records use the include's origin, which ends with the include
+        assert_eq!(records[6].name, DnsName::new("mail.example.org".into()));
+        assert_eq!(records[6].data, RData::A(Ipv4Addr::new(192, 0, 2, 25)));
+        assert_eq!(
+            records[7].name,
+            DnsName::new("after.sub.example.com".into())
+        );
+
+        let long_label = format!("$TTL 60\n{} A 192.0.2.1\n", "x".repeat(64));
+        for bad in [
+            "www A 192.0.2.1\n",
+            "$TTL 60\n@ A (192.0.2.1\n",
+            "$TTL 60\n@ BOGUS x\n",
+            &long_label,
+        ] {
+            assert!(parse_str(bad, &origin, Path::new(".")).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,611 @@
+use std::{
+    fs,
+    io::Write,
+    net::{IpAddr, SocketAddr, TcpStream},
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::{
+    dns::{
+        answer::DnsAnswer,
+        common::{DnsClass, DnsName, DnsType},
+        header::{DnsHeaderQR, DnsHeaderRD, DnsHeaderRcode},
+        message::DnsMessage,
+        question::DnsQuestion,
+        rdata::{DnsSoa, RData},
+    },
+    forwarder::{new_request, query_upstream},
+    server::tcp::read_before,
+};
+
+use super::{
+    catalog::Catalog, notify::notify_secondaries, serial_is_newer, Zone, ZoneDiff, ZonePolicy,
+};
+
+/// How long to wait between attempts while we hold no copy of the zone, and so have no SOA
+/// timers to go by.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+/// How long a whole transfer may take by default, however steadily the primary keeps sending.
+const MAX_TRANSFER_TIME: Duration = Duration::from_secs(30 * 60);
+
+/// Records a transfer may carry by default, all of which are held in memory until it's done.
+const MAX_TRANSFER_RECORDS: usize = 1_000_000;
+
+/// What a primary sent back for a transfer request.
+#[derive(Debug)]
+enum Transfer {
+    UpToDate,
+    Full(Vec<DnsAnswer>),
+    Incremental(Vec<ZoneDiff>),
+}
+
+/// Set when a check of the primaries is due now rather than when the current timer runs out.
+type Wakeup = Arc<(Mutex<bool>, Condvar)>;
+
+/// Lets whoever receives a NOTIFY for a secondary zone have it checked straight away.
+#[derive(Debug, Clone)]
+pub struct SecondaryHandle {
+    // addresses NOTIFY is accepted from, those of the zone's primaries (RFC 1996 section 3.10)
+    pub primaries: Vec<IpAddr>,
+    wakeup: Wakeup,
+}
+
+impl SecondaryHandle {
+    /// Has the secondary check its primaries' SOA now.
+    pub fn notify(&self) {
+        let (due, condvar) = &*self.wakeup;
+        *due.lock().unwrap() = true;
+        condvar.notify_one();
+    }
+}
+
+/// A zone we serve a copy of, pulled from its primaries and kept fresh on the timers in its SOA
+/// (RFC 1034 section 4.3.5).
+#[derive(Debug, Clone)]
+pub struct Secondary {
+    pub origin: DnsName,
+    pub primaries: Vec<SocketAddr>,
+    // where the copy is kept between runs, so a restart serves it before the first refresh
+    pub path: Option<PathBuf>,
+    pub policy: ZonePolicy,
+    // how long to wait for each reply from a primary, and each message of a transfer
+    pub timeout: Duration,
+    // limits on a single transfer, past which it's abandoned and the refresh fails
+    pub max_transfer_time: Duration,
+    pub max_transfer_records: usize,
+    wakeup: Wakeup,
+}
+
+impl Secondary {
+    pub fn new(
+        origin: DnsName,
+        primaries: Vec<SocketAddr>,
+        path: Option<PathBuf>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            origin,
+            primaries,
+            path,
+            policy: ZonePolicy::default(),
+            timeout,
+            max_transfer_time: MAX_TRANSFER_TIME,
+            max_transfer_records: MAX_TRANSFER_RECORDS,
+            wakeup: Wakeup::default(),
+        }
+    }
+
+    pub fn handle(&self) -> SecondaryHandle {
+        SecondaryHandle {
+            primaries: self.primaries.iter().map(SocketAddr::ip).collect(),
+            wakeup: self.wakeup.clone(),
+        }
+    }
+
+    /// Sleeps for `duration`, or until a NOTIFY comes in through our handle. Returns whether it
+    /// was woken early.
+    pub fn wait(&self, duration: Duration) -> bool {
+        let (due, condvar) = &*self.wakeup;
+        let due = due.lock().unwrap();
+        let (mut due, _) = condvar
+            .wait_timeout_while(due, duration, |due| !*due)
+            .unwrap();
+        std::mem::take(&mut *due)
+    }
+
+    /// Keeps the zone in `catalog` fresh for as long as the process runs: checking the primaries
+    /// every refresh interval or when they send a NOTIFY, every retry interval while they can't be
+    /// reached, and marking the zone expired once it has gone unrefreshed for the expire interval.
+    pub fn run(&self, catalog: &Catalog) {
+        let mut last_refresh = self.load_saved(catalog);
+
+        loop {
+            let wait = match self.refresh(catalog) {
+                Ok(_) => {
+                    last_refresh = Some(SystemTime::now());
+                    self.timer(catalog, |soa| soa.refresh)
+                }
+                Err(e) => {
+                    eprintln!("Failed to refresh zone {}: {:#}", self.origin, e);
+                    if let Some(last_refresh) = last_refresh {
+                        self.expire(catalog, last_refresh, SystemTime::now());
+                    }
+                    self.timer(catalog, |soa| soa.retry)
+                }
+            };
+            self.wait(wait);
+        }
+    }
+
+    fn timer(&self, catalog: &Catalog, interval: fn(&DnsSoa) -> u32) -> Duration {
+        catalog.get(&self.origin).map_or(INITIAL_RETRY, |zone| {
+            Duration::from_secs(interval(zone.soa()).into())
+        })
+    }
+
+    /// Serves the copy an earlier run saved, if there is one, and returns when it was saved.
+    pub fn load_saved(&self, catalog: &Catalog) -> Option<SystemTime> {
+        let path = self.path.as_ref().filter(|path| path.exists())?;
+        let loaded = Zone::load(path, &self.origin).and_then(|zone| {
+            if zone.origin != self.origin {
+                bail!("{} holds zone {}", path.display(), zone.origin);
+            }
+            Ok((zone, fs::metadata(path)?.modified()?))
+        });
+
+        match loaded {
+            Ok((mut zone, saved)) => {
+                zone.policy = self.policy.clone();
+                catalog.insert(zone);
+                Some(saved)
+            }
+            Err(e) => {
+                eprintln!("Ignoring saved copy of {}: {:#}", self.origin, e);
+                None
+            }
+        }
+    }
+
+    /// Marks the zone in `catalog` expired if it has gone longer than its expire interval without
+    /// a refresh, since by then it can't be trusted to be current. It stays in the catalog,
+    /// answering SERVFAIL rather than being looked up elsewhere, until a transfer succeeds.
+    /// Returns whether it expired just now.
+    pub fn expire(&self, catalog: &Catalog, last_refresh: SystemTime, now: SystemTime) -> bool {
+        let Some(zone) = catalog.get(&self.origin).filter(|zone| !zone.expired) else {
+            return false;
+        };
+        let expire = Duration::from_secs(zone.soa().expire.into());
+        let elapsed = now.duration_since(last_refresh).unwrap_or_default();
+        if elapsed < expire {
+            return false;
+        }
+
+        eprintln!("Zone {} expired, answering SERVFAIL for it", self.origin);
+        let mut expired = Zone::clone(&zone);
+        expired.expired = true;
+        catalog.insert(expired);
+        true
+    }
+
+    /// Asks the primaries, in order, for their SOA and transfers the zone from the first that
+    /// answers if its serial is newer than ours. Returns whether our copy was replaced.
+    pub fn refresh(&self, catalog: &Catalog) -> anyhow::Result<bool> {
+        // an expired copy is only replaced by a whole new transfer, whatever the serials say
+        let current = catalog.get(&self.origin).filter(|zone| !zone.expired);
+        let mut last_error = None;
+        for primary in &self.primaries {
+            match self.refresh_from(*primary, current.as_deref(), catalog) {
+                Ok(replaced) => return Ok(replaced),
+                Err(e) => last_error = Some(e.context(format!("primary {}", primary))),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No primaries for zone {}", self.origin)))
+    }
+
+    fn refresh_from(
+        &self,
+        primary: SocketAddr,
+        current: Option<&Zone>,
+        catalog: &Catalog,
+    ) -> anyhow::Result<bool> {
+        let serial = self.query_serial(primary)?;
+        if current.is_some_and(|zone| !serial_is_newer(serial, zone.serial())) {
+            return Ok(false);
+        }
+
+        let mut zone = match current {
+            Some(current) => match self.ixfr(primary, current) {
+                Ok(Some(zone)) => zone,
+                Ok(None) => return Ok(false),
+                Err(e) => {
+                    eprintln!(
+                        "IXFR of {} from {} failed, falling back to AXFR: {:#}",
+                        self.origin, primary, e
+                    );
+                    self.axfr(primary)?.with_history(current)
+                }
+            },
+            None => self.axfr(primary)?,
+        };
+        zone.policy = self.policy.clone();
+
+        // a copy we failed to save is still worth serving
+        if let Some(path) = &self.path {
+            if let Err(e) = zone.save(path) {
+                eprintln!("Failed to save zone {}: {:#}", self.origin, e);
+            }
+        }
+        eprintln!("Transferred zone {} serial {}", self.origin, zone.serial());
+        notify_secondaries(&zone);
+        catalog.insert(zone);
+        Ok(true)
+    }
+
+    fn question(&self, qtype: DnsType) -> DnsQuestion {
+        DnsQuestion::new(&self.origin.name, qtype, DnsClass::IN)
+    }
+
+    fn query_serial(&self, primary: SocketAddr) -> anyhow::Result<u32> {
+        let request = new_request(
+            &self.question(DnsType::SOA),
+            DnsHeaderRD::RecursionNotDesired,
+            false,
+        );
+        let reply = query_upstream(primary, &request, self.timeout)?;
+        if reply.header.rcode != DnsHeaderRcode::NoError {
+            bail!("SOA query answered {:?}", reply.header.rcode);
+        }
+
+        reply
+            .answers
+            .iter()
+            .find_map(|answer| match &answer.data {
+                RData::SOA(soa) if answer.name == self.origin => Some(soa.serial),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("SOA query answered without the SOA"))
+    }
+
+    fn axfr(&self, primary: SocketAddr) -> anyhow::Result<Zone> {
+        let request = new_request(
+            &self.question(DnsType::AXFR),
+            DnsHeaderRD::RecursionNotDesired,
+            false,
+        );
+        let mut records = self.read_transfer(primary, &request, |records| {
+            records.len() > 1 && records.last().unwrap().qtype == DnsType::SOA
+        })?;
+
+        if records[0].qtype != DnsType::SOA {
+            bail!("AXFR of {} doesn't start with the SOA", self.origin);
+        }
+        records.pop();
+        Zone::new(self.origin.clone(), records)
+    }
+
+    /// Asks for the changes since our copy, which the primary may answer with the whole zone
+    /// instead. `None` if it says we're already up to date.
+    fn ixfr(&self, primary: SocketAddr, current: &Zone) -> anyhow::Result<Option<Zone>> {
+        let mut request = new_request(
+            &self.question(DnsType::IXFR),
+            DnsHeaderRD::RecursionNotDesired,
+            false,
+        );
+        request.authorities = vec![current.soa_record().clone()];
+
+        let serial = current.serial();
+        let records = self.read_transfer(primary, &request, |records| {
+            parse_ixfr(records, serial).is_some()
+        })?;
+        match parse_ixfr(&records, serial) {
+            Some(Transfer::UpToDate) => Ok(None),
+            Some(Transfer::Full(records)) => {
+                Zone::new(self.origin.clone(), records).map(|zone| Some(zone.with_history(current)))
+            }
+            Some(Transfer::Incremental(diffs)) => current.apply(&diffs).map(Some),
+            None => unreachable!("reading stops once the transfer is complete"),
+        }
+    }
+
+    /// Sends a transfer request over TCP and collects the answers of every message in reply until
+    /// `complete` says the transfer is over. Each message has to arrive within the timeout, and
+    /// the transfer as a whole within its own limits on time and records, so a primary that
+    /// never finishes can't keep us reading.
+    fn read_transfer(
+        &self,
+        primary: SocketAddr,
+        request: &DnsMessage,
+        complete: impl Fn(&[DnsAnswer]) -> bool,
+    ) -> anyhow::Result<Vec<DnsAnswer>> {
+        let mut stream = TcpStream::connect_timeout(&primary, self.timeout)
+            .with_context(|| format!("Failed to connect to {}", primary))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let request_buf = request.as_buf();
+        let mut framed = (request_buf.len() as u16).to_be_bytes().to_vec();
+        framed.extend(request_buf);
+        stream.write_all(&framed)?;
+
+        let deadline = Instant::now() + self.max_transfer_time;
+        let mut records = vec![];
+        while !complete(&records) {
+            if Instant::now() >= deadline {
+                bail!("Transfer took longer than {:?}", self.max_transfer_time);
+            }
+            let message_deadline = deadline.min(Instant::now() + self.timeout);
+            let mut length = [0u8; 2];
+            read_before(&mut stream, &mut length, message_deadline)
+                .context("Transfer ended early")?;
+            let mut buf = vec![0; u16::from_be_bytes(length) as usize];
+            read_before(&mut stream, &mut buf, message_deadline)?;
+
+            let reply = DnsMessage::try_from(&buf[..]).context("Failed to parse transfer")?;
+            if reply.header.query_response != DnsHeaderQR::Reply
+                || reply.header.id != request.header.id
+            {
+                bail!("{} sent a mismatched transfer reply", primary);
+            }
+            if reply.header.rcode != DnsHeaderRcode::NoError {
+                bail!("Transfer answered {:?}", reply.header.rcode);
+            }
+            if reply.answers.is_empty() {
+                bail!("Transfer reply carried no records");
+            }
+            records.extend(reply.answers);
+            if records.len() > self.max_transfer_records {
+                bail!(
+                    "Transfer carried more than {} records",
+                    self.max_transfer_records
+                );
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// Makes sense of the records of an IXFR reply to a request from `serial` (RFC 1995 section 4),
+/// or `None` while more of them are still to come.
+fn parse_ixfr(records: &[DnsAnswer], serial: u32) -> Option<Transfer> {
+    let soa_serial = |record: &DnsAnswer| match &record.data {
+        RData::SOA(soa) => Some(soa.serial),
+        _ => None,
+    };
+    let is_soa = |record: &DnsAnswer| record.qtype == DnsType::SOA;
+
+    // a lone SOA no newer than ours means there's nothing to transfer
+    let newest = soa_serial(records.first()?)?;
+    if records.len() == 1 {
+        return (!serial_is_newer(newest, serial)).then_some(Transfer::UpToDate);
+    }
+
+    // differences start with our own SOA; anything else is the whole zone, AXFR style
+    if records.get(1).and_then(soa_serial) != Some(serial) {
+        let end = records[1..].iter().position(is_soa)? + 1;
+        return (end == records.len() - 1).then(|| Transfer::Full(records[..end].to_vec()));
+    }
+
+    // each difference runs old SOA, deletions, new SOA, additions, and the newest SOA once more
+    // closes the lot
+    let mut diffs = vec![];
+    let mut rest = &records[1..];
+    loop {
+        if soa_serial(rest.first()?)? == newest {
+            return (rest.len() == 1).then_some(Transfer::Incremental(diffs));
+        }
+        let new_soa = rest[1..].iter().position(is_soa)? + 1;
+        let next = rest[new_soa + 1..].iter().position(is_soa)? + new_soa + 1;
+        diffs.push(ZoneDiff {
+            old_soa: rest[0].clone(),
+            deleted: rest[1..new_soa].to_vec(),
+            new_soa: rest[new_soa].clone(),
+            added: rest[new_soa + 1..next].to_vec(),
+        });
+        rest = &rest[next..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        io::{Read, Write},
+        net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket},
+        path::Path,
+        sync::Arc,
+        thread,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{parse_ixfr, Secondary, Transfer};
+    use crate::{
+        cache::Cache,
+        dns::{
+            common::{DnsClass, DnsName, DnsType},
+            header::{DnsHeader, DnsHeaderQR, DnsHeaderRcode},
+            message::DnsMessage,
+            question::DnsQuestion,
+        },
+        server::{tcp, udp, Server},
+        zone::{catalog::Catalog, parser::parse_str, serial_is_newer, Zone},
+    };
+
+    fn primary_zone(serial: u32, extra: &str) -> Zone {
+        let text = format!(
+            "$TTL 300\n@ SOA ns1 hostmaster {serial} 3600 600 86400 60\n  NS ns1\nns1 A 192.0.2.1\n{extra}"
+        );
+        let origin = DnsName::new("example.com".into());
+        let mut zone = Zone::new(
+            origin.clone(),
+            parse_str(&text, &origin, Path::new(".")).unwrap(),
+        )
+        .unwrap();
+        zone.policy.allow_transfer = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        zone
+    }
+
+    /// A primary that starts every transfer with the SOA and then keeps sending other records,
+    /// never the closing SOA.
+    fn spawn_endless_primary() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut length = [0u8; 2];
+                    stream.read_exact(&mut length).unwrap();
+                    let mut buf = vec![0; u16::from_be_bytes(length) as usize];
+                    stream.read_exact(&mut buf).unwrap();
+
+                    let zone = primary_zone(1, "");
+                    let mut reply = DnsMessage::try_from(&buf[..]).unwrap();
+                    reply.header.query_response = DnsHeaderQR::Reply;
+                    reply.answers = vec![zone.soa_record().clone()];
+                    loop {
+                        let buf = reply.as_buf();
+                        let mut framed = (buf.len() as u16).to_be_bytes().to_vec();
+                        framed.extend(buf);
+                        if stream.write_all(&framed).is_err() {
+                            break;
+                        }
+                        reply.answers = zone.records().skip(1).cloned().collect();
+                        thread::sleep(Duration::from_millis(5));
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_secondary_abandons_endless_transfers() {
+        let addr = spawn_endless_primary();
+        let origin = DnsName::new("example.com".into());
+        let mut secondary = Secondary::new(origin, vec![addr], None, Duration::from_secs(1));
+
+        secondary.max_transfer_records = 50;
+        let err = secondary.axfr(addr).unwrap_err();
+        assert!(
+            err.to_string().contains("more than 50 records"),
+            "{:#}",
+            err
+        );
+
+        // records arriving well within the timeout still can't stretch the transfer out
+        secondary.max_transfer_records = usize::MAX;
+        secondary.max_transfer_time = Duration::from_millis(300);
+        assert!(secondary.axfr(addr).is_err());
+    }
+
+    #[test]
+    fn test_secondary_follows_primary() {
+        assert!(serial_is_newer(2, 1));
+        assert!(serial_is_newer(0, u32::MAX));
+        assert!(!serial_is_newer(1, 1));
+        assert!(!serial_is_newer(1 << 31, 0));
+
+        // a primary on loopback serving UDP and TCP on the same port
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).unwrap();
+        let catalog = Catalog::new();
+        catalog.insert(primary_zone(4294967290, ""));
+        let primary = Arc::new(Server::new(catalog, None, None, Cache::new(16, 4096)));
+        let config = tcp::TcpConfig {
+            idle_timeout: Duration::from_secs(1),
+            max_connections: 4,
+        };
+        let tcp_primary = primary.clone();
+        thread::spawn(move || tcp::serve(listener, tcp_primary, config));
+        let udp_primary = primary.clone();
+        thread::spawn(move || udp::serve(socket.into(), udp_primary, 4));
+
+        let dir = env::temp_dir().join(format!("zone-secondary-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let origin = DnsName::new("example.com".into());
+        let secondary = Secondary::new(
+            origin.clone(),
+            vec![addr],
+            Some(dir.join("example.com.zone")),
+            Duration::from_secs(1),
+        );
+
+        let catalog = Catalog::new();
+        assert!(secondary.refresh(&catalog).unwrap());
+        assert_eq!(catalog.get(&origin).unwrap().serial(), 4294967290);
+        assert!(!secondary.refresh(&catalog).unwrap());
+
+        // the serial wraps around and still counts as newer
+        primary
+            .catalog
+            .insert(primary_zone(5, "www A 192.0.2.10\n"));
+        assert!(secondary.refresh(&catalog).unwrap());
+        let zone = catalog.get(&origin).unwrap();
+        assert_eq!(zone.serial(), 5);
+        assert!(zone.node(&DnsName::new("www.example.com".into())).is_some());
+
+        // too long without a refresh and the zone answers SERVFAIL until the next transfer
+        let now = SystemTime::now();
+        assert!(!secondary.expire(&catalog, now - Duration::from_secs(3600), now));
+        assert!(secondary.expire(&catalog, now - Duration::from_secs(86400), now));
+        assert!(!secondary.expire(&catalog, now - Duration::from_secs(86400), now));
+        let expired = catalog.get(&origin).unwrap();
+        assert!(expired.expired);
+        let server = Server::new(Catalog::new(), None, None, Cache::new(16, 4096));
+        server.catalog.insert(Zone::clone(&expired));
+        let header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        let question = DnsQuestion::new("www.example.com", DnsType::A, DnsClass::IN);
+        let query = DnsMessage::new(header, vec![question], vec![], vec![], vec![]);
+        let response = server
+            .handle_query(&query, IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .unwrap();
+        assert_eq!(response.header.rcode, DnsHeaderRcode::ServerFailure);
+        assert!(secondary.refresh(&catalog).unwrap());
+        assert!(!catalog.get(&origin).unwrap().expired);
+
+        // the transferred copy was kept on disk
+        assert!(secondary.load_saved(&catalog).is_some());
+        assert_eq!(catalog.get(&origin).unwrap().serial(), 5);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // incremental replies are split into their differences
+        let records: Vec<_> = [
+            primary_zone(3, ""),
+            primary_zone(1, ""),
+            primary_zone(2, ""),
+        ]
+        .iter()
+        .map(|zone| zone.soa_record().clone())
+        .collect();
+        let www = primary_zone(2, "www A 192.0.2.10\n")
+            .rrset(&DnsName::new("www.example.com".into()), DnsType::A)
+            .next()
+            .unwrap()
+            .clone();
+        let reply = [
+            records[0].clone(),
+            records[1].clone(),
+            records[2].clone(),
+            www,
+            records[2].clone(),
+            records[0].clone(),
+            records[0].clone(),
+        ];
+        assert!(parse_ixfr(&reply[..6], 1).is_none());
+        let Some(Transfer::Incremental(diffs)) = parse_ixfr(&reply, 1) else {
+            panic!("expected an incremental transfer");
+        };
+        assert_eq!(diffs.len(), 2);
+        assert!(diffs[0].deleted.is_empty());
+        assert_eq!(diffs[0].added.len(), 1);
+        assert!(diffs[1].added.is_empty());
+        let zone = primary_zone(1, "").apply(&diffs).unwrap();
+        assert_eq!(zone.serial(), 3);
+        assert!(zone.node(&DnsName::new("www.example.com".into())).is_some());
+    }
+}
@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+
+use crate::dns::{
+    answer::DnsAnswer,
+    common::{DnsClass, DnsName, DnsType},
+    header::DnsHeaderRcode,
+    message::DnsMessage,
+    rdata::RData,
+};
+
+use super::{serial_is_newer, Zone};
+
+/// Carries out a dynamic update against `zone` (RFC 2136 section 3): checks the prerequisites in
+/// the answer section, then applies the update section in the authority section to a copy, with
+/// the SOA serial moved on. Returns the new version of the zone, `None` if nothing changed, or the
+/// RCODE to refuse the whole update with, in which case nothing is applied.
+pub fn update(zone: &Zone, request: &DnsMessage) -> Result<Option<Zone>, DnsHeaderRcode> {
+    check_prerequisites(zone, &request.answers)?;
+    check_updates(zone, &request.authorities)?;
+
+    let mut soa = zone.soa_record().clone();
+    let mut records: Vec<DnsAnswer> = zone.records().skip(1).cloned().collect();
+    let mut changed = false;
+    for record in &request.authorities {
+        changed |= match record.qclass {
+            DnsClass::ANY => delete_rrsets(zone, &mut records, record),
+            DnsClass::NONE => delete_record(zone, &mut records, record),
+            _ => add_record(zone, &mut soa, &mut records, record),
+        };
+    }
+    if !changed {
+        return Ok(None);
+    }
+
+    // every change gets a new serial, unless the update brought one of its own
+    let RData::SOA(new_soa) = &mut soa.data else {
+        unreachable!("SOA records always carry SOA RDATA");
+    };
+    if !serial_is_newer(new_soa.serial, zone.serial()) {
+        new_soa.serial = zone.serial().wrapping_add(1);
+    }
+
+    records.push(soa);
+    let mut updated = Zone::new(zone.origin.clone(), records)
+        .map_err(|e| {
+            eprintln!("Failed to update zone {}: {:#}", zone.origin, e);
+            DnsHeaderRcode::ServerFailure
+        })?
+        .with_history(zone);
+    updated.policy = zone.policy.clone();
+    Ok(Some(updated))
+}
+
+/// Whether RDATA is absent, as it is where an update names an RRset rather than a record.
+fn is_empty(data: &RData) -> bool {
+    matches!(data, RData::Unknown(data) if data.is_empty())
+}
+
+/// Types that stand for several others or for an operation, which can't be stored in a zone.
+fn is_meta(qtype: DnsType) -> bool {
+    matches!(
+        qtype,
+        DnsType::ANY | DnsType::AXFR | DnsType::IXFR | DnsType::OPT
+    )
+}
+
+/// Checks every prerequisite against the zone as it is (RFC 2136 section 3.2). Class ANY asks for
+/// a name or RRset to exist, class NONE for it not to, and the zone's class for an RRset to be
+/// exactly the records given.
+fn check_prerequisites(zone: &Zone, prerequisites: &[DnsAnswer]) -> Result<(), DnsHeaderRcode> {
+    let mut expected: HashMap<(&DnsName, DnsType), Vec<&RData>> = HashMap::new();
+    for record in prerequisites {
+        if record.ttl != 0 {
+            return Err(DnsHeaderRcode::FormatError);
+        }
+        if !record.name.is_subdomain_of(&zone.origin) {
+            return Err(DnsHeaderRcode::NotZone);
+        }
+
+        let in_use = zone
+            .node(&record.name)
+            .is_some_and(|records| !records.is_empty());
+        let rrset_exists = zone.rrset(&record.name, record.qtype).next().is_some();
+        match record.qclass {
+            DnsClass::ANY | DnsClass::NONE if !is_empty(&record.data) => {
+                return Err(DnsHeaderRcode::FormatError)
+            }
+            DnsClass::ANY if record.qtype == DnsType::ANY && !in_use => {
+                return Err(DnsHeaderRcode::NameError)
+            }
+            DnsClass::ANY if record.qtype != DnsType::ANY && !rrset_exists => {
+                return Err(DnsHeaderRcode::NXRRSet)
+            }
+            DnsClass::NONE if record.qtype == DnsType::ANY && in_use => {
+                return Err(DnsHeaderRcode::YXDomain)
+            }
+            DnsClass::NONE if record.qtype != DnsType::ANY && rrset_exists => {
+                return Err(DnsHeaderRcode::YXRRSet)
+            }
+            DnsClass::ANY | DnsClass::NONE => {}
+            class if class == zone.class => expected
+                .entry((&record.name, record.qtype))
+                .or_default()
+                .push(&record.data),
+            _ => return Err(DnsHeaderRcode::FormatError),
+        }
+    }
+
+    // value-dependent prerequisites only hold once all records of each RRset are known
+    for ((name, qtype), values) in expected {
+        let rrset: Vec<&RData> = zone.rrset(name, qtype).map(|record| &record.data).collect();
+        if !values.iter().all(|value| rrset.contains(value))
+            || !rrset.iter().all(|value| values.contains(value))
+        {
+            return Err(DnsHeaderRcode::NXRRSet);
+        }
+    }
+    Ok(())
+}
+
+/// Checks the update section is well formed before any of it is applied (RFC 2136 section
+/// 3.4.1.3).
+fn check_updates(zone: &Zone, updates: &[DnsAnswer]) -> Result<(), DnsHeaderRcode> {
+    for record in updates {
+        if !record.name.is_subdomain_of(&zone.origin) {
+            return Err(DnsHeaderRcode::NotZone);
+        }
+        let valid = match record.qclass {
+            // delete an RRset, or with type ANY every RRset at the name
+            DnsClass::ANY => {
+                record.ttl == 0
+                    && is_empty(&record.data)
+                    && (record.qtype == DnsType::ANY || !is_meta(record.qtype))
+            }
+            // delete one record
+            DnsClass::NONE => record.ttl == 0 && !is_meta(record.qtype),
+            // add one record
+            class => class == zone.class && !is_meta(record.qtype),
+        };
+        if !valid {
+            return Err(DnsHeaderRcode::FormatError);
+        }
+    }
+    Ok(())
+}
+
+/// Adds a record unless it would put a CNAME beside other data, or replaces the SOA if the new
+/// one has a later serial (RFC 2136 section 3.4.2.2). Records the zone couldn't serve are ignored
+/// the same way: a CNAME at the apex, and anything that would end up hidden by a zone cut. The
+/// TTL of the RRset becomes the record's.
+fn add_record(
+    zone: &Zone,
+    soa: &mut DnsAnswer,
+    records: &mut Vec<DnsAnswer>,
+    update: &DnsAnswer,
+) -> bool {
+    if update.qtype == DnsType::SOA {
+        let newer = matches!(
+            (&update.data, &soa.data),
+            (RData::SOA(new), RData::SOA(current)) if serial_is_newer(new.serial, current.serial)
+        );
+        if update.name == zone.origin && newer {
+            *soa = update.clone();
+        }
+        return update.name == zone.origin && newer;
+    }
+
+    let conflict = records.iter().any(|record| {
+        record.name == update.name
+            && record.qtype != update.qtype
+            && (record.qtype == DnsType::CNAME || update.qtype == DnsType::CNAME)
+    });
+    if conflict || update.name == zone.origin && update.qtype == DnsType::CNAME {
+        return false;
+    }
+
+    // nothing goes at or below an existing cut that it would hide, and no new cut goes above
+    // records it would hide
+    let is_cut = |name: &DnsName| {
+        *name != zone.origin
+            && records
+                .iter()
+                .any(|record| record.name == *name && record.qtype == DnsType::NS)
+    };
+    let below_cut = records
+        .iter()
+        .filter(|record| is_cut(&record.name))
+        .any(|cut| is_occluded(update, &cut.name));
+    let over_records = update.qtype == DnsType::NS
+        && update.name != zone.origin
+        && !is_cut(&update.name)
+        && records
+            .iter()
+            .any(|record| is_occluded(record, &update.name));
+    if below_cut || over_records {
+        return false;
+    }
+
+    let mut changed = false;
+    if update.qtype == DnsType::CNAME {
+        // a name has one alias at most, so a new one replaces the old
+        let before = records.len();
+        records.retain(|record| {
+            record.name != update.name
+                || record.qtype != DnsType::CNAME
+                || record.data == update.data
+        });
+        changed = records.len() != before;
+    }
+    for record in records
+        .iter_mut()
+        .filter(|record| record.name == update.name && record.qtype == update.qtype)
+    {
+        if record.ttl != update.ttl {
+            record.ttl = update.ttl;
+            changed = true;
+        }
+    }
+    if !records.iter().any(|record| {
+        record.name == update.name && record.qtype == update.qtype && record.data == update.data
+    }) {
+        records.push(update.clone());
+        changed = true;
+    }
+    changed
+}
+
+/// Whether `record` would be hidden by a zone cut at `cut`. Only the delegation's own NS records
+/// belong at a cut, and only glue addresses for its name servers below one.
+fn is_occluded(record: &DnsAnswer, cut: &DnsName) -> bool {
+    if !record.name.is_subdomain_of(cut) {
+        return false;
+    }
+    match record.qtype {
+        DnsType::A | DnsType::AAAA => false,
+        DnsType::NS => record.name != *cut,
+        _ => true,
+    }
+}
+
+/// Deletes the RRset of the update's type at its name, or every RRset there for type ANY. The SOA
+/// and the apex NS RRset are never deleted this way (RFC 2136 section 3.4.2.3).
+fn delete_rrsets(zone: &Zone, records: &mut Vec<DnsAnswer>, update: &DnsAnswer) -> bool {
+    let before = records.len();
+    records.retain(|record| {
+        record.name != update.name
+            || (update.qtype != DnsType::ANY && record.qtype != update.qtype)
+            || (record.name == zone.origin && record.qtype == DnsType::NS)
+    });
+    records.len() != before
+}
+
+/// Deletes one record, unless it's the SOA or the last NS at the apex (RFC 2136 section 3.4.2.4).
+fn delete_record(zone: &Zone, records: &mut Vec<DnsAnswer>, update: &DnsAnswer) -> bool {
+    if update.qtype == DnsType::SOA {
+        return false;
+    }
+    let apex_ns = records
+        .iter()
+        .filter(|record| record.name == zone.origin && record.qtype == DnsType::NS)
+        .count();
+    if update.name == zone.origin && update.qtype == DnsType::NS && apex_ns == 1 {
+        return false;
+    }
+
+    let Some(position) = records.iter().position(|record| {
+        record.name == update.name && record.qtype == update.qtype && record.data == update.data
+    }) else {
+        return false;
+    };
+    records.swap_remove(position);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        net::{IpAddr, Ipv4Addr},
+        path::Path,
+    };
+
+    use crate::{
+        cache::Cache,
+        dns::{
+            answer::DnsAnswer,
+            common::{DnsClass, DnsName, DnsType},
+            header::{DnsHeader, DnsHeaderOpcode, DnsHeaderRcode},
+            message::DnsMessage,
+            question::DnsQuestion,
+            rdata::RData,
+        },
+        server::Server,
+        zone::{catalog::Catalog, parser::parse_str, Zone},
+    };
+
+    #[test]
+    fn test_update_checks_prerequisites_and_applies_changes() {
+        let origin = DnsName::new("example.com".into());
+        let text = "$TTL 300\n@ SOA ns1 hostmaster 1 3600 600 86400 60\n  NS ns1\n  MX 10 mail\n\
+                    www A 192.0.2.10\nalias CNAME www\ninfo TXT \"hello\"\n\
+                    sub NS ns.sub\nns.sub A 192.0.2.50\n";
+        let mut zone = Zone::new(
+            origin.clone(),
+            parse_str(text, &origin, Path::new(".")).unwrap(),
+        )
+        .unwrap();
+        let client = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        zone.policy.allow_update = vec![client];
+        let catalog = Catalog::new();
+        catalog.insert(zone);
+        let server = Server::new(catalog, None, None, Cache::new(16, 4096));
+
+        let a = |name: &str, class: DnsClass, ttl: u32, address: [u8; 4]| {
+            DnsAnswer::new(name, DnsType::A, class, ttl, RData::A(address.into()))
+        };
+        let empty = |name: &str, qtype: DnsType, class: DnsClass| {
+            DnsAnswer::new(name, qtype, class, 0, RData::Unknown(vec![]))
+        };
+        // sent through the wire format, where RRsets are named by empty RDATA
+        let send = |zone: &str, prerequisites: Vec<DnsAnswer>, updates: Vec<DnsAnswer>| {
+            let mut header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+            header.opcode = DnsHeaderOpcode::Update;
+            let zone_section = DnsQuestion::new(zone, DnsType::SOA, DnsClass::IN);
+            let request =
+                DnsMessage::new(header, vec![zone_section], prerequisites, updates, vec![]);
+            let request = DnsMessage::try_from(&request.as_buf()[..]).unwrap();
+            let response = server.handle_query(&request, client).unwrap();
+            assert_eq!(response.header.opcode, DnsHeaderOpcode::Update);
+            response.header.rcode
+        };
+        let serial = || server.catalog.get(&origin).unwrap().serial();
+        let rrset = |name: &str, qtype| -> Vec<RData> {
+            let zone = server.catalog.get(&origin).unwrap();
+            zone.rrset(&DnsName::new(name.into()), qtype)
+                .map(|record| record.data.clone())
+                .collect()
+        };
+
+        // www has an address and new doesn't exist yet, so both records go in under serial 2
+        let rcode = send(
+            "example.com",
+            vec![
+                empty("www.example.com", DnsType::A, DnsClass::ANY),
+                empty("new.example.com", DnsType::ANY, DnsClass::NONE),
+            ],
+            vec![
+                a("new.example.com", DnsClass::IN, 60, [192, 0, 2, 20]),
+                a("www.example.com", DnsClass::IN, 300, [192, 0, 2, 11]),
+            ],
+        );
+        assert_eq!(rcode, DnsHeaderRcode::NoError);
+        assert_eq!(serial(), 2);
+        assert_eq!(rrset("new.example.com", DnsType::A).len(), 1);
+        assert_eq!(rrset("www.example.com", DnsType::A).len(), 2);
+        let zone = server.catalog.get(&origin).unwrap();
+        assert_eq!(zone.changes_since(1).unwrap()[0].added.len(), 2);
+
+        // a failed prerequisite leaves the zone alone
+        let add = || vec![a("more.example.com", DnsClass::IN, 60, [192, 0, 2, 30])];
+        let failures = [
+            (
+                vec![a("www.example.com", DnsClass::IN, 0, [192, 0, 2, 10])],
+                DnsHeaderRcode::NXRRSet,
+            ),
+            (
+                vec![empty("missing.example.com", DnsType::ANY, DnsClass::ANY)],
+                DnsHeaderRcode::NameError,
+            ),
+            (
+                vec![empty("www.example.com", DnsType::ANY, DnsClass::NONE)],
+                DnsHeaderRcode::YXDomain,
+            ),
+            (
+                vec![empty("www.example.com", DnsType::A, DnsClass::NONE)],
+                DnsHeaderRcode::YXRRSet,
+            ),
+            (
+                vec![empty("www.example.org", DnsType::A, DnsClass::ANY)],
+                DnsHeaderRcode::NotZone,
+            ),
+        ];
+        for (prerequisites, expected) in failures {
+            assert_eq!(send("example.com", prerequisites, add()), expected);
+        }
+        assert_eq!(serial(), 2);
+        assert!(rrset("more.example.com", DnsType::A).is_empty());
+
+        // the whole RRset as a value-dependent prerequisite holds
+        let both = vec![
+            a("www.example.com", DnsClass::IN, 0, [192, 0, 2, 11]),
+            a("www.example.com", DnsClass::IN, 0, [192, 0, 2, 10]),
+        ];
+        assert_eq!(send("example.com", both, add()), DnsHeaderRcode::NoError);
+        assert_eq!(serial(), 3);
+
+        // deletes: one record, an RRset, and everything at the apex but the SOA and NS
+        let rcode = send(
+            "example.com",
+            vec![],
+            vec![
+                a("www.example.com", DnsClass::NONE, 0, [192, 0, 2, 10]),
+                empty("new.example.com", DnsType::A, DnsClass::ANY),
+                empty("example.com", DnsType::ANY, DnsClass::ANY),
+            ],
+        );
+        assert_eq!(rcode, DnsHeaderRcode::NoError);
+        assert_eq!(serial(), 4);
+        assert_eq!(
+            rrset("www.example.com", DnsType::A),
+            [RData::A(Ipv4Addr::new(192, 0, 2, 11))]
+        );
+        assert!(server
+            .catalog
+            .get(&origin)
+            .unwrap()
+            .node(&DnsName::new("new.example.com".into()))
+            .is_none());
+        assert!(rrset("example.com", DnsType::MX).is_empty());
+        assert_eq!(rrset("example.com", DnsType::NS).len(), 1);
+
+        // the last apex NS stays, and nothing goes next to a CNAME, so neither changes the zone
+        let ns = DnsAnswer::new(
+            "example.com",
+            DnsType::NS,
+            DnsClass::NONE,
+            0,
+            RData::NS(DnsName::new("ns1.example.com".into())),
+        );
+        let beside_alias = a("alias.example.com", DnsClass::IN, 60, [192, 0, 2, 40]);
+        assert_eq!(
+            send("example.com", vec![], vec![ns, beside_alias]),
+            DnsHeaderRcode::NoError
+        );
+        assert_eq!(serial(), 4);
+
+        // nor does a CNAME at the apex, data a delegation would hide, or a delegation over data
+        let txt = |name: &str| {
+            DnsAnswer::new(
+                name,
+                DnsType::TXT,
+                DnsClass::IN,
+                60,
+                RData::TXT(vec![b"hello".to_vec()]),
+            )
+        };
+        let apex_alias = DnsAnswer::new(
+            "example.com",
+            DnsType::CNAME,
+            DnsClass::IN,
+            60,
+            RData::CNAME(DnsName::new("www.example.com".into())),
+        );
+        let over_info = DnsAnswer::new(
+            "info.example.com",
+            DnsType::NS,
+            DnsClass::IN,
+            60,
+            RData::NS(DnsName::new("ns1.example.com".into())),
+        );
+        let hidden = vec![
+            apex_alias,
+            txt("sub.example.com"),
+            txt("host.sub.example.com"),
+            over_info,
+        ];
+        assert_eq!(send("example.com", vec![], hidden), DnsHeaderRcode::NoError);
+        assert_eq!(serial(), 4);
+        assert!(rrset("info.example.com", DnsType::NS).is_empty());
+
+        // glue below a delegation is fine
+        let glue = vec![a("ns2.sub.example.com", DnsClass::IN, 60, [192, 0, 2, 51])];
+        assert_eq!(send("example.com", vec![], glue), DnsHeaderRcode::NoError);
+        assert_eq!(serial(), 5);
+
+        // malformed updates, other zones and other clients are turned away
+        let any_type = empty("www.example.com", DnsType::ANY, DnsClass::IN);
+        assert_eq!(
+            send("example.com", vec![], vec![any_type]),
+            DnsHeaderRcode::FormatError
+        );
+        assert_eq!(send("example.org", vec![], add()), DnsHeaderRcode::NotAuth);
+        let mut header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+        header.opcode = DnsHeaderOpcode::Update;
+        let zone_section = DnsQuestion::new("example.com", DnsType::SOA, DnsClass::IN);
+        let request = DnsMessage::new(header, vec![zone_section], vec![], add(), vec![]);
+        let stranger = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(
+            server.handle_query(&request, stranger).unwrap().header.rcode,
+            DnsHeaderRcode::Refused
+        );
+        assert_eq!(serial(), 5);
+    }
+
+    #[test]
+    fn test_update_is_saved_before_it_is_served() {
+        let origin = DnsName::new("example.com".into());
+        let text = "$TTL 300\n@ SOA ns1 hostmaster 1 3600 600 86400 60\n  NS ns1\n";
+        let mut zone = Zone::new(
+            origin.clone(),
+            parse_str(text, &origin, Path::new(".")).unwrap(),
+        )
+        .unwrap();
+        let client = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        zone.policy.allow_update = vec![client];
+        let catalog = Catalog::new();
+        catalog.insert(zone);
+        let mut server = Server::new(catalog, None, None, Cache::new(16, 4096));
+
+        let dir = env::temp_dir().join(format!("zone-update-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.com.zone");
+        server.zone_files.insert(origin.clone(), path.clone());
+
+        let send = |server: &Server, address: [u8; 4]| {
+            let mut header = DnsHeader::try_from(&[0u8; 12][..]).unwrap();
+            header.opcode = DnsHeaderOpcode::Update;
+            let zone_section = DnsQuestion::new("example.com", DnsType::SOA, DnsClass::IN);
+            let record = DnsAnswer::new(
+                "www.example.com",
+                DnsType::A,
+                DnsClass::IN,
+                60,
+                RData::A(address.into()),
+            );
+            let request = DnsMessage::new(header, vec![zone_section], vec![], vec![record], vec![]);
+            server.handle_query(&request, client).unwrap().header.rcode
+        };
+
+        assert_eq!(send(&server, [192, 0, 2, 10]), DnsHeaderRcode::NoError);
+        let saved = Zone::load(&path, &origin).unwrap();
+        assert_eq!(saved.serial(), 2);
+        assert_eq!(
+            saved
+                .rrset(&DnsName::new("www.example.com".into()), DnsType::A)
+                .count(),
+            1
+        );
+
+        // a change that can't be saved isn't made
+        server
+            .zone_files
+            .insert(origin.clone(), dir.join("missing").join("example.com.zone"));
+        assert_eq!(
+            send(&server, [192, 0, 2, 11]),
+            DnsHeaderRcode::ServerFailure
+        );
+        assert_eq!(server.catalog.get(&origin).unwrap().serial(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}